@@ -14,8 +14,6 @@ sudo docker run --rm "$DOCKER_TAG" \
 tar -c \
 app \
 codepong \
-handlebars \
-static \
 | gzip > "app_packages/codepong_$GIT_COMMIT_SHORT.tar.gz"
 
 sudo docker build -f app_package_Dockerfile -t generic_app_host:latest .
@@ -0,0 +1,409 @@
+use std::{
+	convert::TryFrom,
+	io::{self, Write},
+};
+
+use anyhow::{
+	Context,
+	bail,
+};
+use git2::{
+	Oid,
+	Repository,
+};
+use hyper::body::Bytes;
+use tokio::sync::mpsc;
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+	TarGz,
+	Zip,
+}
+
+impl Format {
+	/// Splits a name like `main.tar.gz` into the rev and the format
+	pub fn split (name: &str) -> Option <(&str, Self)> {
+		if let Some (rev) = name.strip_suffix (".tar.gz") {
+			Some ((rev, Self::TarGz))
+		}
+		else {
+			name.strip_suffix (".zip").map (|rev| (rev, Self::Zip))
+		}
+	}
+	
+	pub fn extension (self) -> &'static str {
+		match self {
+			Self::TarGz => "tar.gz",
+			Self::Zip => "zip",
+		}
+	}
+	
+	pub fn content_type (self) -> &'static str {
+		match self {
+			Self::TarGz => "application/gzip",
+			Self::Zip => "application/zip",
+		}
+	}
+}
+
+/// A file, directory or symlink from the commit's tree
+struct Item {
+	path: String,
+	kind: Kind,
+	id: Oid,
+}
+
+#[derive (Clone, Copy, PartialEq)]
+enum Kind {
+	Dir,
+	File,
+	Executable,
+	Symlink,
+}
+
+/// Writes the tree of `commit` to `out` as an archive, with everything
+/// inside `prefix/`. Every entry gets the commit's time, so the same
+/// commit always makes the same bytes.
+pub fn write <W: Write> (repo: &Repository, commit: Oid, prefix: &str, format: Format, out: W) -> anyhow::Result <()> {
+	let commit = repo.find_commit (commit)?;
+	let time = commit.time ().seconds ().max (0) as u64;
+	
+	let mut items = vec! [Item {
+		path: format! ("{}/", prefix),
+		kind: Kind::Dir,
+		id: commit.tree_id (),
+	}];
+	collect (repo, &commit.tree ()?, &format! ("{}/", prefix), &mut items)?;
+	
+	match format {
+		Format::TarGz => write_tar_gz (repo, &items, time, out),
+		Format::Zip => write_zip (repo, &items, time, out),
+	}
+}
+
+/// Lists everything in `tree`, directories before what's in them.
+/// Submodules become empty directories, like in `git archive`.
+fn collect (repo: &Repository, tree: &git2::Tree, prefix: &str, items: &mut Vec <Item>) -> anyhow::Result <()> {
+	for entry in tree.iter () {
+		let path = format! ("{}{}", prefix, String::from_utf8_lossy (entry.name_bytes ()));
+		let mode = entry.filemode ();
+		
+		match mode & 0o170000 {
+			0o040000 => {
+				let path = format! ("{}/", path);
+				items.push (Item {
+					path: path.clone (),
+					kind: Kind::Dir,
+					id: entry.id (),
+				});
+				collect (repo, &repo.find_tree (entry.id ())?, &path, items)?;
+			},
+			0o160000 => items.push (Item {
+				path: format! ("{}/", path),
+				kind: Kind::Dir,
+				id: entry.id (),
+			}),
+			0o120000 => items.push (Item {
+				path,
+				kind: Kind::Symlink,
+				id: entry.id (),
+			}),
+			_ => items.push (Item {
+				path,
+				kind: if mode & 0o111 != 0 { Kind::Executable } else { Kind::File },
+				id: entry.id (),
+			}),
+		}
+	}
+	
+	Ok (())
+}
+
+fn write_tar_gz <W: Write> (repo: &Repository, items: &[Item], time: u64, out: W) -> anyhow::Result <()> {
+	let mut tar = tar::Builder::new (flate2::write::GzEncoder::new (out, flate2::Compression::default ()));
+	
+	for item in items {
+		let mut header = tar::Header::new_gnu ();
+		header.set_mtime (time);
+		header.set_uid (0);
+		header.set_gid (0);
+		
+		match item.kind {
+			Kind::Dir => {
+				header.set_entry_type (tar::EntryType::Directory);
+				header.set_mode (0o755);
+				header.set_size (0);
+				tar.append_data (&mut header, &item.path, io::empty ())?;
+			},
+			Kind::Symlink => {
+				let blob = repo.find_blob (item.id)?;
+				let target = String::from_utf8_lossy (blob.content ()).to_string ();
+				header.set_entry_type (tar::EntryType::Symlink);
+				header.set_mode (0o777);
+				header.set_size (0);
+				tar.append_link (&mut header, &item.path, target)?;
+			},
+			Kind::File | Kind::Executable => {
+				let blob = repo.find_blob (item.id)?;
+				header.set_entry_type (tar::EntryType::Regular);
+				header.set_mode (if item.kind == Kind::Executable { 0o755 } else { 0o644 });
+				header.set_size (blob.size () as u64);
+				tar.append_data (&mut header, &item.path, blob.content ())?;
+			},
+		}
+	}
+	
+	tar.into_inner ()?.finish ()?.flush ()?;
+	Ok (())
+}
+
+/// Zip writers usually seek back to fill in each entry's size. Here each
+/// file is compressed in memory first, so its size is known before its
+/// header goes out and the archive can be written front to back.
+///
+/// There's no ZIP64, so anything past 4 GB or 65,535 entries is an error
+/// instead of a broken zip. Game repos are nowhere near that.
+fn write_zip <W: Write> (repo: &Repository, items: &[Item], time: u64, mut out: W) -> anyhow::Result <()> {
+	// Names are UTF-8
+	const FLAGS: u16 = 1 << 11;
+	const STORED: u16 = 0;
+	const DEFLATED: u16 = 8;
+	
+	struct Written {
+		name: Vec <u8>,
+		method: u16,
+		crc: u32,
+		compressed_size: u32,
+		size: u32,
+		offset: u32,
+		mode: u32,
+	}
+	
+	let (dos_time, dos_date) = dos_date_time (time);
+	let mut offset: u64 = 0;
+	let mut written = vec! [];
+	
+	for item in items {
+		let (mode, method, data, size, crc) = match item.kind {
+			Kind::Dir => (0o040755, STORED, vec! [], 0, 0),
+			Kind::Symlink => {
+				let blob = repo.find_blob (item.id)?;
+				(0o120777, STORED, blob.content ().to_vec (), blob.size (), crc32fast::hash (blob.content ()))
+			},
+			Kind::File | Kind::Executable => {
+				let blob = repo.find_blob (item.id)?;
+				let mut deflate = flate2::write::DeflateEncoder::new (vec! [], flate2::Compression::default ());
+				deflate.write_all (blob.content ())?;
+				let mode = if item.kind == Kind::Executable { 0o100755 } else { 0o100644 };
+				(mode, DEFLATED, deflate.finish ()?, blob.size (), crc32fast::hash (blob.content ()))
+			},
+		};
+		
+		let entry = Written {
+			name: item.path.as_bytes ().to_vec (),
+			method,
+			crc,
+			compressed_size: u32::try_from (data.len ()).context ("File too big for a zip")?,
+			size: u32::try_from (size).context ("File too big for a zip")?,
+			offset: u32::try_from (offset).context ("Archive too big for a zip")?,
+			mode,
+		};
+		
+		let mut header = vec! [];
+		header.extend_from_slice (&0x04034b50_u32.to_le_bytes ());
+		header.extend_from_slice (&20_u16.to_le_bytes ());
+		header.extend_from_slice (&FLAGS.to_le_bytes ());
+		header.extend_from_slice (&entry.method.to_le_bytes ());
+		header.extend_from_slice (&dos_time.to_le_bytes ());
+		header.extend_from_slice (&dos_date.to_le_bytes ());
+		header.extend_from_slice (&entry.crc.to_le_bytes ());
+		header.extend_from_slice (&entry.compressed_size.to_le_bytes ());
+		header.extend_from_slice (&entry.size.to_le_bytes ());
+		header.extend_from_slice (&(entry.name.len () as u16).to_le_bytes ());
+		header.extend_from_slice (&0_u16.to_le_bytes ());
+		header.extend_from_slice (&entry.name);
+		
+		out.write_all (&header)?;
+		out.write_all (&data)?;
+		offset += (header.len () + data.len ()) as u64;
+		written.push (entry);
+	}
+	
+	if written.len () > u16::MAX as usize {
+		bail! ("Too many files for a zip");
+	}
+	
+	let directory_offset = u32::try_from (offset).context ("Archive too big for a zip")?;
+	let mut directory = vec! [];
+	for entry in &written {
+		directory.extend_from_slice (&0x02014b50_u32.to_le_bytes ());
+		// Made by Unix, so the mode in the external attributes counts
+		directory.extend_from_slice (&((3 << 8) | 20_u16).to_le_bytes ());
+		directory.extend_from_slice (&20_u16.to_le_bytes ());
+		directory.extend_from_slice (&FLAGS.to_le_bytes ());
+		directory.extend_from_slice (&entry.method.to_le_bytes ());
+		directory.extend_from_slice (&dos_time.to_le_bytes ());
+		directory.extend_from_slice (&dos_date.to_le_bytes ());
+		directory.extend_from_slice (&entry.crc.to_le_bytes ());
+		directory.extend_from_slice (&entry.compressed_size.to_le_bytes ());
+		directory.extend_from_slice (&entry.size.to_le_bytes ());
+		directory.extend_from_slice (&(entry.name.len () as u16).to_le_bytes ());
+		// Extra field, comment, disk number, internal attributes
+		directory.extend_from_slice (&[0; 8]);
+		directory.extend_from_slice (&(entry.mode << 16).to_le_bytes ());
+		directory.extend_from_slice (&entry.offset.to_le_bytes ());
+		directory.extend_from_slice (&entry.name);
+	}
+	
+	let count = (written.len () as u16).to_le_bytes ();
+	let mut end = vec! [];
+	end.extend_from_slice (&0x06054b50_u32.to_le_bytes ());
+	// This disk and the disk where the directory starts
+	end.extend_from_slice (&[0; 4]);
+	end.extend_from_slice (&count);
+	end.extend_from_slice (&count);
+	end.extend_from_slice (&u32::try_from (directory.len ()).context ("Archive too big for a zip")?.to_le_bytes ());
+	end.extend_from_slice (&directory_offset.to_le_bytes ());
+	end.extend_from_slice (&0_u16.to_le_bytes ());
+	
+	out.write_all (&directory)?;
+	out.write_all (&end)?;
+	out.flush ()?;
+	Ok (())
+}
+
+/// MS-DOS time and date, which can't go before 1980
+fn dos_date_time (time: u64) -> (u16, u16) {
+	use chrono::{Datelike, NaiveDateTime, Timelike};
+	
+	let t = NaiveDateTime::from_timestamp (time.max (315_532_800) as i64, 0);
+	let dos_time = ((t.hour () << 11) | (t.minute () << 5) | (t.second () / 2)) as u16;
+	let dos_date = (((t.year () as u32 - 1980) << 9) | (t.month () << 5) | t.day ()) as u16;
+	(dos_time, dos_date)
+}
+
+/// Sends what's written to it to a response body, in chunks. It's written
+/// from a blocking thread, and it fails once the client goes away, which
+/// stops the archive early.
+pub struct BodyWriter {
+	tx: mpsc::Sender <io::Result <Bytes>>,
+	buf: Vec <u8>,
+}
+
+impl BodyWriter {
+	const CHUNK: usize = 64 * 1024;
+	
+	pub fn new (tx: mpsc::Sender <io::Result <Bytes>>) -> Self {
+		Self {
+			tx,
+			buf: Vec::with_capacity (Self::CHUNK),
+		}
+	}
+}
+
+impl Write for BodyWriter {
+	fn write (&mut self, data: &[u8]) -> io::Result <usize> {
+		self.buf.extend_from_slice (data);
+		if self.buf.len () >= Self::CHUNK {
+			self.flush ()?;
+		}
+		Ok (data.len ())
+	}
+	
+	fn flush (&mut self) -> io::Result <()> {
+		if self.buf.is_empty () {
+			return Ok (());
+		}
+		
+		let chunk = Bytes::from (std::mem::replace (&mut self.buf, Vec::with_capacity (Self::CHUNK)));
+		self.tx.blocking_send (Ok (chunk))
+		.map_err (|_| io::Error::new (io::ErrorKind::BrokenPipe, "Client went away"))
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn archiving () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let repo = Repository::init_bare (dir.path ().join ("repo.git")).unwrap ();
+		let sig = git2::Signature::new ("alice", "alice@example.com", &git2::Time::new (1_600_000_000, 0)).unwrap ();
+		
+		let mut js = repo.treebuilder (None).unwrap ();
+		js.insert ("pong.js", repo.blob (b"pong ()\n").unwrap (), 0o100644).unwrap ();
+		let js = js.write ().unwrap ();
+		let mut tree = repo.treebuilder (None).unwrap ();
+		tree.insert ("js", js, 0o040000).unwrap ();
+		tree.insert ("game.html", repo.blob (b"<p>Pong</p>\n").unwrap (), 0o100644).unwrap ();
+		tree.insert ("run.sh", repo.blob (b"#!/bin/sh\n").unwrap (), 0o100755).unwrap ();
+		tree.insert ("index.html", repo.blob (b"game.html").unwrap (), 0o120000).unwrap ();
+		let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+		let commit = repo.commit (None, &sig, &sig, "Start", &tree, &[]).unwrap ();
+		
+		let archive = |format| {
+			let mut out = vec! [];
+			write (&repo, commit, "abcd1234", format, &mut out).unwrap ();
+			out
+		};
+		
+		// Same commit, same bytes
+		let tar_gz = archive (Format::TarGz);
+		assert_eq! (tar_gz, archive (Format::TarGz));
+		let zip = archive (Format::Zip);
+		assert_eq! (zip, archive (Format::Zip));
+		
+		let mut tar = tar::Archive::new (flate2::read::GzDecoder::new (&tar_gz [..]));
+		let mut entries = vec! [];
+		for entry in tar.entries ().unwrap () {
+			let mut entry = entry.unwrap ();
+			let mut content = String::new ();
+			io::Read::read_to_string (&mut entry, &mut content).unwrap ();
+			let header = entry.header ();
+			assert_eq! (header.mtime ().unwrap (), 1_600_000_000);
+			
+			// A symlink's content is its target
+			if let Some (target) = entry.link_name ().unwrap () {
+				content = target.display ().to_string ();
+			}
+			entries.push ((entry.path ().unwrap ().display ().to_string (), header.mode ().unwrap (), content));
+		}
+		
+		let expected = vec! [
+			("abcd1234/".to_string (), 0o755, String::new ()),
+			("abcd1234/game.html".to_string (), 0o644, "<p>Pong</p>\n".to_string ()),
+			("abcd1234/index.html".to_string (), 0o777, "game.html".to_string ()),
+			("abcd1234/js/".to_string (), 0o755, String::new ()),
+			("abcd1234/js/pong.js".to_string (), 0o644, "pong ()\n".to_string ()),
+			("abcd1234/run.sh".to_string (), 0o755, "#!/bin/sh\n".to_string ()),
+		];
+		assert_eq! (entries, expected);
+		
+		// Reading it back checks the central directory, the sizes and the
+		// CRCs against what's really there
+		let mut zip = zip::ZipArchive::new (io::Cursor::new (zip)).unwrap ();
+		let mut entries = vec! [];
+		for i in 0..zip.len () {
+			let mut file = zip.by_index (i).unwrap ();
+			let mut content = String::new ();
+			io::Read::read_to_string (&mut file, &mut content).unwrap ();
+			
+			let modified = file.last_modified ();
+			assert_eq! ((modified.year (), modified.month (), modified.day ()), (2020, 9, 13));
+			
+			let kind = match file.is_dir () {
+				true => 0o040000,
+				false if file.name () == "abcd1234/index.html" => 0o120000,
+				false => 0o100000,
+			};
+			assert_eq! (file.unix_mode ().unwrap () & 0o170000, kind, "{}", file.name ());
+			entries.push ((file.name ().to_string (), file.unix_mode ().unwrap () & 0o777, content));
+		}
+		assert_eq! (entries, expected);
+		
+		assert_eq! (Format::split ("main.tar.gz"), Some (("main", Format::TarGz)));
+		assert_eq! (Format::split ("abc.zip"), Some (("abc", Format::Zip)));
+		assert_eq! (Format::split ("main.tar"), None);
+	}
+}
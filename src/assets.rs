@@ -0,0 +1,68 @@
+use std::{
+	borrow::Cow,
+	time::SystemTime,
+};
+
+/// A static file that was compiled into the binary
+#[cfg_attr (not (feature = "embed-assets"), allow (dead_code))]
+pub struct EmbeddedFile {
+	pub data: Cow <'static, [u8]>,
+	pub etag: String,
+	pub modified: Option <SystemTime>,
+}
+
+#[cfg (feature = "embed-assets")]
+mod embedded {
+	#[derive (rust_embed::RustEmbed)]
+	#[folder = "static/"]
+	pub struct Static;
+	
+	#[derive (rust_embed::RustEmbed)]
+	#[folder = "handlebars/"]
+	pub struct Templates;
+}
+
+/// Looks up a file that was embedded from `static/`. `path` must already
+/// be cleaned up by `safe_path::relative_path`, with `/` as separator.
+#[cfg (feature = "embed-assets")]
+pub fn static_file (path: &str) -> Option <EmbeddedFile> {
+	let file = embedded::Static::get (path)?;
+	
+	let hash: String = file.metadata.sha256_hash ().iter ()
+	.map (|b| format! ("{:02x}", b))
+	.collect ();
+	
+	Some (EmbeddedFile {
+		data: file.data,
+		etag: format! ("\"{}\"", hash),
+		modified: file.metadata.last_modified ()
+		.map (|secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs (secs)),
+	})
+}
+
+#[cfg (not (feature = "embed-assets"))]
+pub fn static_file (_path: &str) -> Option <EmbeddedFile> {
+	None
+}
+
+/// Embedded Handlebars files directly inside `dir` (e.g. `""` or
+/// `"partials/"`), as (name, source) pairs
+#[cfg (feature = "embed-assets")]
+pub fn templates (dir: &str) -> Vec <(String, String)> {
+	embedded::Templates::iter ()
+	.filter_map (|path| {
+		let name = path.strip_prefix (dir)?
+		.strip_suffix (".hbs")
+		.filter (|name| ! name.contains ('/'))?
+		.to_string ();
+		let file = embedded::Templates::get (&path)?;
+		let source = String::from_utf8 (file.data.into_owned ()).ok ()?;
+		Some ((name, source))
+	})
+	.collect ()
+}
+
+#[cfg (not (feature = "embed-assets"))]
+pub fn templates (_dir: &str) -> Vec <(String, String)> {
+	vec! []
+}
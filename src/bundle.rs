@@ -0,0 +1,166 @@
+use std::{
+	io::Write,
+	path::Path,
+};
+
+use git2::{
+	Oid,
+	Repository,
+};
+
+use crate::error::Error;
+
+/// Unpacks a `git bundle` into the repo at `repo_path` and returns the
+/// commit that its `branch` points to. Made for players who can't host a
+/// public remote:
+///
+/// `git bundle create pong.bundle main`, or to keep it small,
+/// `git bundle create pong.bundle origin/main..main`
+///
+/// Nothing is trusted before the pack has been indexed, which checks
+/// every object's hash, and the bundle's prerequisites have to be in our
+/// repo already. `repo_path` is meant to be a quarantine, so the pack is
+/// thrown away with it unless the commit is accepted.
+pub fn unbundle (repo_path: &Path, bundle: &[u8], branch: &str) -> Result <Oid, Error> {
+	let parsed = parse (bundle, branch)?;
+	let repo = Repository::open (repo_path)?;
+	let odb = repo.odb ()?;
+	
+	for prereq in &parsed.prerequisites {
+		if ! odb.exists (*prereq) {
+			return Err (Error::bad_request (format! ("The bundle needs commit {}, which isn't on the server. Bundle from the server's `{}` instead.", prereq, branch)));
+		}
+	}
+	
+	let mut writer = odb.packwriter ()?;
+	writer.write_all (parsed.pack)
+	.and_then (|_| writer.commit ().map_err (std::io::Error::other))
+	.map_err (|e| Error::bad_request (format! ("The bundle's pack is broken: {}", e)))?;
+	
+	// No ref is made for the tip. The caller checks the commit and moves
+	// the branch itself.
+	let commit = repo.find_commit (parsed.tip)
+	.map_err (|_| Error::bad_request (format! ("The bundle's `{}` isn't a commit", branch)))?;
+	
+	Ok (commit.id ())
+}
+
+struct Parsed <'a> {
+	prerequisites: Vec <Oid>,
+	tip: Oid,
+	pack: &'a [u8],
+}
+
+fn parse <'a> (bundle: &'a [u8], branch: &str) -> Result <Parsed <'a>, Error> {
+	let bad = |msg: &str| Error::bad_request (format! ("That isn't a Git bundle: {}", msg));
+	
+	let header_end = bundle.windows (2).position (|w| w == b"\n\n")
+	.ok_or_else (|| bad ("no header"))?;
+	let header = std::str::from_utf8 (&bundle [..header_end])
+	.map_err (|_| bad ("header isn't UTF-8"))?;
+	let pack = &bundle [header_end + 2..];
+	
+	let mut lines = header.lines ();
+	match lines.next () {
+		Some ("# v2 git bundle") | Some ("# v3 git bundle") => (),
+		_ => return Err (bad ("unknown version")),
+	}
+	
+	let mut prerequisites = vec! [];
+	let refname = format! ("refs/heads/{}", branch);
+	let mut tip = None;
+	
+	for line in lines {
+		if let Some (capability) = line.strip_prefix ('@') {
+			// v3 bundles name their hash, and we only speak SHA-1
+			if capability != "object-format=sha1" {
+				return Err (bad (&format! ("unsupported capability `{}`", capability)));
+			}
+		}
+		else if let Some (prereq) = line.strip_prefix ('-') {
+			let oid = prereq.split (' ').next ().unwrap_or ("");
+			prerequisites.push (Oid::from_str (oid).map_err (|_| bad ("bad prerequisite"))?);
+		}
+		else {
+			let (oid, name) = line.split_once (' ').ok_or_else (|| bad ("bad ref line"))?;
+			if name == refname {
+				tip = Some (Oid::from_str (oid).map_err (|_| bad ("bad ref"))?);
+			}
+		}
+	}
+	
+	if ! pack.starts_with (b"PACK") {
+		return Err (bad ("no pack"));
+	}
+	
+	Ok (Parsed {
+		prerequisites,
+		tip: tip.ok_or_else (|| Error::bad_request (format! ("The bundle has no `{}` branch", branch)))?,
+		pack,
+	})
+}
+
+#[cfg (test)]
+mod tests {
+	use std::process::Command;
+	
+	use super::*;
+	
+	fn git (dir: &Path, args: &[&str]) {
+		let status = Command::new ("git")
+		.args (args)
+		.current_dir (dir)
+		.env ("GIT_AUTHOR_NAME", "alice")
+		.env ("GIT_AUTHOR_EMAIL", "alice@example.com")
+		.env ("GIT_COMMITTER_NAME", "alice")
+		.env ("GIT_COMMITTER_EMAIL", "alice@example.com")
+		.status ()
+		.unwrap ();
+		assert! (status.success (), "git {:?}", args);
+	}
+	
+	#[test]
+	fn unbundling () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let server = dir.path ().join ("server");
+		let player = dir.path ().join ("player");
+		
+		std::fs::create_dir (&server).unwrap ();
+		git (&server, &["init", "-q", "-b", "main"]);
+		std::fs::write (server.join ("game.html"), "<p>Pong</p>").unwrap ();
+		git (&server, &["add", "."]);
+		git (&server, &["commit", "-q", "-m", "Start"]);
+		
+		git (dir.path (), &["clone", "-q", server.to_str ().unwrap (), "player"]);
+		std::fs::write (player.join ("game.html"), "<p>Pong, but faster</p>").unwrap ();
+		git (&player, &["commit", "-q", "-am", "Faster"]);
+		git (&player, &["bundle", "create", "-q", "../thin.bundle", "origin/main..main"]);
+		
+		let player_head = Repository::open (&player).unwrap ().refname_to_id ("refs/heads/main").unwrap ();
+		let bundle = std::fs::read (dir.path ().join ("thin.bundle")).unwrap ();
+		
+		assert_eq! (unbundle (&server, &bundle, "main").unwrap (), player_head);
+		let repo = Repository::open (&server).unwrap ();
+		assert! (repo.find_commit (player_head).is_ok ());
+		assert! (repo.references_glob ("refs/codepong/*").unwrap ().next ().is_none ());
+		
+		// Thin bundle for a server that doesn't have the base commit
+		let empty = dir.path ().join ("empty");
+		Repository::init (&empty).unwrap ();
+		assert! (unbundle (&empty, &bundle, "main").is_err ());
+		assert! (unbundle (&server, &bundle, "side").is_err ());
+		
+		// A full bundle works there, unless it's been tampered with
+		git (&player, &["bundle", "create", "-q", "../full.bundle", "main"]);
+		let full = std::fs::read (dir.path ().join ("full.bundle")).unwrap ();
+		let mut broken = full.clone ();
+		let last = broken.len () - 1;
+		broken [last] ^= 0xff;
+		assert! (unbundle (&empty, &broken, "main").is_err ());
+		assert_eq! (unbundle (&empty, &full, "main").unwrap (), player_head);
+		
+		for garbage in [&b""[..], b"PACK", b"# v2 git bundle\n\nPACK", b"# v9 git bundle\nabc refs/heads/main\n\nPACK"] {
+			assert! (unbundle (&server, garbage, "main").is_err ());
+		}
+	}
+}
@@ -0,0 +1,208 @@
+use async_compression::{
+	Level,
+	tokio::bufread::{BrotliEncoder, GzipEncoder},
+};
+use futures_util::TryStreamExt;
+use hyper::{
+	Body,
+	HeaderMap,
+	Response,
+	StatusCode,
+	header::HeaderValue,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Bodies smaller than this aren't worth the CPU or the extra headers
+const MIN_SIZE: u64 = 512;
+
+/// Brotli's default is 11, its slowest, which is meant for compressing
+/// ahead of time. 4 is about as fast as gzip and still a bit smaller.
+const BROTLI_QUALITY: u32 = 4;
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+	Brotli,
+	Gzip,
+}
+
+impl Encoding {
+	pub fn name (self) -> &'static str {
+		match self {
+			Self::Brotli => "br",
+			Self::Gzip => "gzip",
+		}
+	}
+	
+	/// File extension of a precompressed variant, e.g. `style.css.br`
+	pub fn extension (self) -> &'static str {
+		match self {
+			Self::Brotli => "br",
+			Self::Gzip => "gz",
+		}
+	}
+}
+
+/// Picks the best encoding that the client's `Accept-Encoding` allows,
+/// preferring brotli over gzip when the client likes them equally.
+/// Returns every acceptable encoding, best first.
+pub fn negotiate (headers: &HeaderMap) -> Vec <Encoding> {
+	let value = match headers.get ("accept-encoding").and_then (|v| v.to_str ().ok ()) {
+		None => return vec! [],
+		Some (x) => x,
+	};
+	
+	let mut br = None;
+	let mut gzip = None;
+	let mut star = None;
+	
+	for item in value.split (',') {
+		let mut parts = item.split (';');
+		let name = parts.next ().unwrap_or ("").trim ().to_ascii_lowercase ();
+		let q = parts
+		.filter_map (|p| p.trim ().strip_prefix ("q="))
+		.next ()
+		.and_then (|q| q.trim ().parse::<f32> ().ok ())
+		.unwrap_or (1.0);
+		
+		match name.as_str () {
+			"br" => br = Some (q),
+			"gzip" | "x-gzip" => gzip = Some (q),
+			"*" => star = Some (q),
+			_ => (),
+		}
+	}
+	
+	let br = br.or (star).unwrap_or (0.0);
+	let gzip = gzip.or (star).unwrap_or (0.0);
+	
+	let mut encodings = vec! [];
+	if br > 0.0 && br >= gzip {
+		encodings.push (Encoding::Brotli);
+	}
+	if gzip > 0.0 {
+		encodings.push (Encoding::Gzip);
+	}
+	if br > 0.0 && br < gzip {
+		encodings.push (Encoding::Brotli);
+	}
+	
+	encodings
+}
+
+/// Text-like types compress well. Images, audio, video, fonts and Git's
+/// zlib-compressed objects and packs are already compressed.
+pub fn is_compressible (content_type: &str) -> bool {
+	let mime = content_type.split (';').next ().unwrap_or ("").trim ();
+	
+	mime.starts_with ("text/") ||
+	matches! (mime,
+		"application/json" |
+		"application/javascript" |
+		"application/wasm" |
+		"application/xml" |
+		"image/svg+xml" |
+		"image/bmp"
+	)
+}
+
+/// Compresses a response on the fly if the client and the content allow
+/// it. Partial content and already-encoded responses are left alone, and
+/// so are responses to HEAD, since their Content-Length would have to go.
+pub fn compress (req_headers: &HeaderMap, head: bool, mut resp: Response <Body>) -> Response <Body> {
+	let compressible = resp.headers ().get ("content-type")
+	.and_then (|v| v.to_str ().ok ())
+	.map (is_compressible)
+	.unwrap_or (false);
+	
+	if ! compressible {
+		return resp;
+	}
+	
+	// Caches have to know that the body depends on Accept-Encoding, even
+	// when this particular response wasn't compressed
+	resp.headers_mut ().append ("vary", HeaderValue::from_static ("accept-encoding"));
+	
+	let too_small = resp.headers ().get ("content-length")
+	.and_then (|v| v.to_str ().ok ())
+	.and_then (|s| s.parse::<u64> ().ok ())
+	.map (|len| len < MIN_SIZE)
+	.unwrap_or (false);
+	
+	if head ||
+		resp.status () != StatusCode::OK ||
+		resp.headers ().contains_key ("content-encoding") ||
+		too_small
+	{
+		return resp;
+	}
+	
+	let encoding = match negotiate (req_headers).first () {
+		None => return resp,
+		Some (x) => *x,
+	};
+	
+	let (mut parts, body) = resp.into_parts ();
+	
+	let reader = StreamReader::new (body.map_err (std::io::Error::other));
+	let body = match encoding {
+		Encoding::Brotli => Body::wrap_stream (ReaderStream::new (BrotliEncoder::with_quality (reader, Level::Precise (BROTLI_QUALITY)))),
+		Encoding::Gzip => Body::wrap_stream (ReaderStream::new (GzipEncoder::new (reader))),
+	};
+	
+	let headers = &mut parts.headers;
+	headers.remove ("content-length");
+	headers.remove ("accept-ranges");
+	headers.insert ("content-encoding", HeaderValue::from_static (encoding.name ()));
+	
+	// The compressed bytes differ from the original, so a strong ETag
+	// would be a lie
+	if let Some (etag) = headers.get ("etag").and_then (|v| v.to_str ().ok ()) {
+		if ! etag.starts_with ("W/") {
+			if let Ok (weak) = HeaderValue::from_str (&format! ("W/{}", etag)) {
+				headers.insert ("etag", weak);
+			}
+		}
+	}
+	
+	Response::from_parts (parts, body)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	use super::Encoding::*;
+	
+	#[test]
+	fn negotiation () {
+		for (value, expected) in vec! [
+			("", vec! []),
+			("identity", vec! []),
+			("gzip", vec! [Gzip]),
+			("gzip, deflate, br", vec! [Brotli, Gzip]),
+			("br;q=0.5, gzip", vec! [Gzip, Brotli]),
+			("br;q=0, gzip", vec! [Gzip]),
+			("*", vec! [Brotli, Gzip]),
+			("*;q=0.1, gzip;q=0", vec! [Brotli]),
+			("GZIP;q=1.0", vec! [Gzip]),
+		].into_iter () {
+			let mut headers = HeaderMap::new ();
+			headers.insert ("accept-encoding", value.parse ().unwrap ());
+			assert_eq! (negotiate (&headers), expected, "{}", value);
+		}
+		
+		assert_eq! (negotiate (&HeaderMap::new ()), vec! []);
+	}
+	
+	#[test]
+	fn compressibility () {
+		assert! (is_compressible ("text/html; charset=utf-8"));
+		assert! (is_compressible ("text/javascript; charset=utf-8"));
+		assert! (is_compressible ("application/wasm"));
+		assert! (is_compressible ("image/svg+xml; charset=utf-8"));
+		assert! (! is_compressible ("image/png"));
+		assert! (! is_compressible ("audio/ogg"));
+		assert! (! is_compressible ("font/woff2"));
+		assert! (! is_compressible ("application/x-git-packed-objects"));
+		assert! (! is_compressible ("application/octet-stream"));
+	}
+}
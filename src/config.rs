@@ -0,0 +1,272 @@
+use std::{
+	net::{IpAddr, SocketAddr},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{
+	Context,
+	bail,
+};
+use serde::Deserialize;
+
+use crate::proxy::PublicUrl;
+
+/// Server settings from `game/codepong.toml`. Every key is optional, and
+/// without the file at all codepong serves plain HTTP on port 4000, same
+/// as always.
+#[derive (Debug, Default, Deserialize)]
+#[serde (default, deny_unknown_fields)]
+pub struct Config {
+	pub http: Http,
+	pub tls: Option <Tls>,
+	pub rate_limits: RateLimits,
+	pub fetch: Fetch,
+	pub branches: Branches,
+}
+
+#[derive (Debug, Deserialize)]
+#[serde (default, deny_unknown_fields)]
+pub struct Http {
+	/// Serves the whole site, or only redirects to HTTPS if `tls` is set
+	pub listen: SocketAddr,
+	
+	/// Where users reach codepong, like `https://example.com/codepong/`.
+	/// Needed when a reverse proxy puts it under a path prefix.
+	pub public_url: Option <PublicUrl>,
+	
+	/// Reverse proxies whose `X-Forwarded-For` and `X-Forwarded-Proto`
+	/// headers are believed
+	pub trusted_proxies: Vec <IpAddr>,
+}
+
+impl Default for Http {
+	fn default () -> Self {
+		Self {
+			listen: SocketAddr::from (([0, 0, 0, 0], 4000)),
+			public_url: None,
+			trusted_proxies: vec! [],
+		}
+	}
+}
+
+#[derive (Debug, Deserialize)]
+#[serde (deny_unknown_fields)]
+pub struct Tls {
+	#[serde (default = "Tls::default_listen")]
+	pub listen: SocketAddr,
+	
+	/// PEM certificate chain, leaf first. Re-read whenever it changes, so
+	/// renewals don't need a restart.
+	pub cert: PathBuf,
+	
+	/// PEM private key, PKCS #8, RSA or SEC1 EC
+	pub key: PathBuf,
+}
+
+impl Tls {
+	fn default_listen () -> SocketAddr {
+		SocketAddr::from (([0, 0, 0, 0], 4443))
+	}
+}
+
+/// Token buckets for abusable routes. Clients get `burst` requests up
+/// front, then `per_minute` on average. `per_minute = 0` turns a limit off.
+#[derive (Debug, Deserialize)]
+#[serde (default, deny_unknown_fields)]
+pub struct RateLimits {
+	/// Every POST, per client IP
+	pub post_per_ip: Limit,
+	
+	/// Baton and commit POSTs, per username, since each commit makes us
+	/// fetch from someone's remote
+	pub post_per_user: Limit,
+	
+	/// Everything under `git/`, per client IP. A dumb-protocol clone makes
+	/// one request per loose object, so this is generous.
+	pub git_per_ip: Limit,
+}
+
+impl Default for RateLimits {
+	fn default () -> Self {
+		Self {
+			post_per_ip: Limit {
+				burst: 10,
+				per_minute: 6,
+			},
+			post_per_user: Limit {
+				burst: 5,
+				per_minute: 2,
+			},
+			git_per_ip: Limit {
+				burst: 2_000,
+				per_minute: 1_200,
+			},
+		}
+	}
+}
+
+impl RateLimits {
+	fn check (&self) -> anyhow::Result <()> {
+		for (name, limit) in [
+			("post_per_ip", self.post_per_ip),
+			("post_per_user", self.post_per_user),
+			("git_per_ip", self.git_per_ip),
+		] {
+			// An empty bucket never fills up, so that route would be shut
+			// for good
+			if limit.burst == 0 && limit.per_minute > 0 {
+				bail! ("`{}` needs a `burst` of at least 1, or `per_minute = 0` to turn it off", name);
+			}
+		}
+		Ok (())
+	}
+}
+
+#[derive (Clone, Copy, Debug, Deserialize)]
+#[serde (deny_unknown_fields)]
+pub struct Limit {
+	pub burst: u32,
+	pub per_minute: u32,
+}
+
+/// Limits on fetching from players' remotes for `commit`
+#[derive (Debug, Deserialize)]
+#[serde (default, deny_unknown_fields)]
+pub struct Fetch {
+	/// Wall-clock limit for the whole fetch
+	pub timeout_secs: u64,
+	
+	/// Fetches that download more than this are cancelled
+	pub max_bytes: u64,
+	
+	/// If not empty, only these hosts can be fetched from, e.g.
+	/// `["github.com", "gitlab.com"]`
+	pub allowed_hosts: Vec <String>,
+	
+	/// Allows remotes on loopback, private and link-local addresses. Only
+	/// for testing, this lets anyone make us talk to the local network.
+	pub allow_private_addresses: bool,
+}
+
+impl Default for Fetch {
+	fn default () -> Self {
+		Self {
+			timeout_secs: 60,
+			max_bytes: 100_000_000,
+			allowed_hosts: vec! [],
+			allow_private_addresses: false,
+		}
+	}
+}
+
+/// Branches that players take turns on
+#[derive (Debug, Deserialize)]
+#[serde (default, deny_unknown_fields)]
+pub struct Branches {
+	/// The canonical branch, which the repo's `HEAD` points to
+	pub main: String,
+	
+	/// Extra "side pong" branches, each with a baton of its own. Missing
+	/// ones are started from `main`.
+	pub side: Vec <String>,
+}
+
+impl Default for Branches {
+	fn default () -> Self {
+		Self {
+			main: "main".to_string (),
+			side: vec! [],
+		}
+	}
+}
+
+impl Branches {
+	/// Every branch, `main` first
+	pub fn all (&self) -> impl Iterator <Item = &str> {
+		std::iter::once (self.main.as_str ()).chain (self.side.iter ().map (|s| s.as_str ()))
+	}
+	
+	fn check (&self) -> anyhow::Result <()> {
+		for (i, name) in self.all ().enumerate () {
+			// They show up in URLs like `tree/<branch>/`
+			if ! crate::is_valid_rev (name) {
+				bail! ("`{}` can't be used as a branch name", name);
+			}
+			if self.all ().take (i).any (|x| x == name) {
+				bail! ("Branch `{}` is listed twice", name);
+			}
+		}
+		Ok (())
+	}
+}
+
+impl Config {
+	pub fn load (path: &Path) -> anyhow::Result <Self> {
+		let s = match std::fs::read_to_string (path) {
+			Err (e) if e.kind () == std::io::ErrorKind::NotFound => return Ok (Self::default ()),
+			x => x.with_context (|| format! ("Can't read config file `{}`", path.display ()))?,
+		};
+		
+		Self::parse (&s)
+		.with_context (|| format! ("Bad config file `{}`", path.display ()))
+	}
+	
+	fn parse (s: &str) -> anyhow::Result <Self> {
+		let config: Self = toml::from_str (s)?;
+		config.rate_limits.check ()?;
+		config.branches.check ()?;
+		Ok (config)
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn parsing () {
+		let config = Config::parse ("").unwrap ();
+		assert_eq! (config.http.listen.port (), 4000);
+		assert! (config.tls.is_none ());
+		assert_eq! (config.rate_limits.post_per_user.per_minute, 2);
+		assert! (config.fetch.allowed_hosts.is_empty ());
+		assert! (! config.fetch.allow_private_addresses);
+		assert_eq! (config.branches.all ().collect::<Vec <_>> (), vec! ["main"]);
+		
+		let config = Config::parse (r#"
+			[http]
+			listen = "0.0.0.0:80"
+			public_url = "https://example.com/codepong/"
+			trusted_proxies = ["127.0.0.1", "::1"]
+			
+			[tls]
+			cert = "game/tls/fullchain.pem"
+			key = "game/tls/privkey.pem"
+			
+			[rate_limits]
+			git_per_ip = { burst = 50, per_minute = 0 }
+			
+			[branches]
+			main = "trunk"
+			side = ["hard-mode"]
+		"#).unwrap ();
+		assert_eq! (config.http.listen.port (), 80);
+		assert_eq! (config.http.public_url.unwrap ().as_str (), "https://example.com/codepong/");
+		assert_eq! (config.http.trusted_proxies.len (), 2);
+		let tls = config.tls.unwrap ();
+		assert_eq! (tls.listen.port (), 4443);
+		assert_eq! (tls.cert, Path::new ("game/tls/fullchain.pem"));
+		assert_eq! (config.rate_limits.git_per_ip.burst, 50);
+		assert_eq! (config.rate_limits.post_per_ip.burst, 10);
+		assert_eq! (config.branches.all ().collect::<Vec <_>> (), vec! ["trunk", "hard-mode"]);
+		
+		// A typo shouldn't silently fall back to plain HTTP
+		assert! (Config::parse ("[tls]\ncert = \"a\"\nkye = \"b\"").is_err ());
+		assert! (Config::parse ("[htttp]").is_err ());
+		assert! (Config::parse ("[http]\npublic_url = \"/codepong/\"").is_err ());
+		assert! (Config::parse ("[rate_limits]\npost_per_ip = { burst = 0, per_minute = 6 }").is_err ());
+		assert! (Config::parse ("[rate_limits]\npost_per_ip = { burst = 0, per_minute = 0 }").is_ok ());
+		assert! (Config::parse ("[branches]\nside = [\"main\"]").is_err ());
+		assert! (Config::parse ("[branches]\nside = [\"side/pong\"]").is_err ());
+	}
+}
@@ -0,0 +1,122 @@
+use std::path::Path;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// How much of a file on disk is read for `guess_from_start`
+pub const SNIFF_BYTES: u64 = 1_024;
+const PLAIN_TEXT: &str = "text/plain; charset=utf-8";
+
+/// Content-Type by lowercase file extension. Text types carry a charset
+/// since game code is expected to be UTF-8.
+const TABLE: &[(&str, &str)] = &[
+	// Documents and code
+	("css", "text/css; charset=utf-8"),
+	("csv", "text/csv; charset=utf-8"),
+	("htm", "text/html; charset=utf-8"),
+	("html", "text/html; charset=utf-8"),
+	("js", "text/javascript; charset=utf-8"),
+	("json", "application/json; charset=utf-8"),
+	("map", "application/json; charset=utf-8"),
+	("md", "text/markdown; charset=utf-8"),
+	("mjs", "text/javascript; charset=utf-8"),
+	("txt", PLAIN_TEXT),
+	("wasm", "application/wasm"),
+	("xml", "application/xml; charset=utf-8"),
+	
+	// Shaders are plain text, but browsers don't know that
+	("frag", PLAIN_TEXT),
+	("glsl", PLAIN_TEXT),
+	("vert", PLAIN_TEXT),
+	
+	// Images
+	("bmp", "image/bmp"),
+	("gif", "image/gif"),
+	("ico", "image/x-icon"),
+	("jpeg", "image/jpeg"),
+	("jpg", "image/jpeg"),
+	("png", "image/png"),
+	("svg", "image/svg+xml; charset=utf-8"),
+	("webp", "image/webp"),
+	
+	// Audio and video
+	("flac", "audio/flac"),
+	("m4a", "audio/mp4"),
+	("mid", "audio/midi"),
+	("midi", "audio/midi"),
+	("mp3", "audio/mpeg"),
+	("mp4", "video/mp4"),
+	("oga", "audio/ogg"),
+	("ogg", "audio/ogg"),
+	("ogv", "video/ogg"),
+	("opus", "audio/ogg"),
+	("wav", "audio/wav"),
+	("webm", "video/webm"),
+	
+	// Fonts
+	("otf", "font/otf"),
+	("ttf", "font/ttf"),
+	("woff", "font/woff"),
+	("woff2", "font/woff2"),
+	
+	// Archives
+	("gz", "application/gzip"),
+	("pdf", "application/pdf"),
+	("zip", "application/zip"),
+];
+
+/// Picks a Content-Type for a file by its extension. If the extension is
+/// unknown and `content` is given, UTF-8 text without NULs is served as
+/// plain text, so things like `LICENSE` show up in the browser.
+pub fn guess (path: &Path, content: Option <&[u8]>) -> &'static str {
+	let ext = path.extension ()
+	.and_then (|ext| ext.to_str ())
+	.map (|ext| ext.to_ascii_lowercase ());
+	
+	if let Some (ext) = ext {
+		if let Some ((_, content_type)) = TABLE.iter ().find (|(e, _)| *e == ext) {
+			return content_type;
+		}
+	}
+	
+	match content {
+		Some (bytes) if ! bytes.contains (&0) && std::str::from_utf8 (bytes).is_ok () => PLAIN_TEXT,
+		_ => OCTET_STREAM,
+	}
+}
+
+/// Like `guess`, but for only the first bytes of a file, as read from
+/// disk. A character cut in half at the end doesn't make it binary.
+pub fn guess_from_start (path: &Path, start: &[u8]) -> &'static str {
+	let start = match std::str::from_utf8 (start) {
+		Err (e) if e.error_len ().is_none () => &start [..e.valid_up_to ()],
+		_ => start,
+	};
+	guess (path, Some (start))
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn guessing () {
+		for (path, content, expected) in vec! [
+			("game.html", None, "text/html; charset=utf-8"),
+			("js/Main.JS", None, "text/javascript; charset=utf-8"),
+			("pong.wasm", None, "application/wasm"),
+			("sfx/boing.ogg", None, "audio/ogg"),
+			("level.json", None, "application/json; charset=utf-8"),
+			("ttf/Glass_TTY_VT220.ttf", None, "font/ttf"),
+			("LICENSE", Some (&b"MIT License"[..]), PLAIN_TEXT),
+			("blob", Some (&b"\x00\x01\x02"[..]), OCTET_STREAM),
+			("blob", None, OCTET_STREAM),
+			(".gitignore", Some (&b"target/"[..]), PLAIN_TEXT),
+		].into_iter () {
+			assert_eq! (guess (Path::new (path), content), expected, "{}", path);
+		}
+		
+		// Cut off in the middle of `é`
+		assert_eq! (guess_from_start (Path::new ("CREDITS"), &"Café".as_bytes () [..4]), PLAIN_TEXT);
+		assert_eq! (guess_from_start (Path::new ("blob"), b"\xff\xfe"), OCTET_STREAM);
+	}
+}
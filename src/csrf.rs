@@ -0,0 +1,203 @@
+use hyper::HeaderMap;
+use ring::{
+	hmac,
+	rand::{SecureRandom, SystemRandom},
+};
+
+use crate::error::Error;
+
+const COOKIE: &str = "codepong_session";
+
+/// Tokens that prove a POST came from a form we served. Each browser
+/// gets a random session cookie, and the token in the form is an HMAC of
+/// it, so another site can neither read nor forge one.
+///
+/// The key is made fresh at startup, so forms from before a restart have
+/// to be reloaded.
+pub struct Csrf {
+	rng: SystemRandom,
+	key: hmac::Key,
+}
+
+/// What a page with a form needs
+pub struct Issued {
+	pub token: String,
+	
+	/// `Set-Cookie` value for browsers that didn't have a session yet
+	pub set_cookie: Option <String>,
+}
+
+impl Csrf {
+	pub fn new () -> anyhow::Result <Self> {
+		let rng = SystemRandom::new ();
+		let key = hmac::Key::generate (hmac::HMAC_SHA256, &rng)
+		.map_err (|_| anyhow::anyhow! ("Can't generate CSRF key"))?;
+		
+		Ok (Self {
+			rng,
+			key,
+		})
+	}
+	
+	/// Token for a form, starting a session if needed. The cookie is
+	/// `SameSite=Strict`, so browsers won't even send it cross-site.
+	pub fn issue (&self, headers: &HeaderMap, base_path: &str, https: bool) -> anyhow::Result <Issued> {
+		if let Some (session) = session (headers) {
+			return Ok (Issued {
+				token: self.token (session),
+				set_cookie: None,
+			});
+		}
+		
+		let mut bytes = [0u8; 32];
+		self.rng.fill (&mut bytes)
+		.map_err (|_| anyhow::anyhow! ("Can't generate session ID"))?;
+		let session = hex (&bytes);
+		
+		let secure = if https { "; Secure" } else { "" };
+		
+		Ok (Issued {
+			token: self.token (&session),
+			set_cookie: Some (format! ("{}={}; Path={}; HttpOnly; SameSite=Strict{}", COOKIE, session, base_path, secure)),
+		})
+	}
+	
+	/// Checks a POST. `origin` is where our own pages are served from,
+	/// if we know it.
+	pub fn verify (&self, headers: &HeaderMap, origin: Option <&str>, token: &str) -> Result <(), Error> {
+		if ! same_origin (headers, origin) {
+			return Err (Error::forbidden ("This form was sent from another site."));
+		}
+		
+		let valid = match (session (headers), unhex (token)) {
+			(Some (session), Some (tag)) => hmac::verify (&self.key, session.as_bytes (), &tag).is_ok (),
+			_ => false,
+		};
+		
+		if ! valid {
+			return Err (Error::forbidden ("This form has expired. Go back, reload the page and try again."));
+		}
+		
+		Ok (())
+	}
+	
+	fn token (&self, session: &str) -> String {
+		hex (hmac::sign (&self.key, session.as_bytes ()).as_ref ())
+	}
+}
+
+fn session (headers: &HeaderMap) -> Option <&str> {
+	headers.get_all ("cookie").iter ()
+	.filter_map (|v| v.to_str ().ok ())
+	.flat_map (|v| v.split (';'))
+	.filter_map (|pair| pair.trim ().split_once ('='))
+	.find (|(name, _)| *name == COOKIE)
+	.map (|(_, value)| value)
+	.filter (|value| value.len () == 64 && value.bytes ().all (|b| b.is_ascii_hexdigit ()))
+}
+
+/// Browsers send `Origin` with every POST these days, older ones at least
+/// send `Referer`. If neither is there, the token alone has to do.
+fn same_origin (headers: &HeaderMap, expected: Option <&str>) -> bool {
+	let expected = match expected {
+		None => return true,
+		Some (x) => x,
+	};
+	
+	if let Some (origin) = headers.get ("origin") {
+		return origin.to_str ().map (|o| o.eq_ignore_ascii_case (expected)).unwrap_or (false);
+	}
+	
+	if let Some (referer) = headers.get ("referer") {
+		let referer = match referer.to_str () {
+			Ok (x) => x,
+			Err (_) => return false,
+		};
+		
+		// The origin is everything before the path
+		let origin_end = referer.find ("://")
+		.map (|i| i + 3)
+		.and_then (|i| referer [i..].find ('/').map (|j| i + j))
+		.unwrap_or (referer.len ());
+		return referer [..origin_end].eq_ignore_ascii_case (expected);
+	}
+	
+	true
+}
+
+fn hex (bytes: &[u8]) -> String {
+	bytes.iter ().map (|b| format! ("{:02x}", b)).collect ()
+}
+
+fn unhex (s: &str) -> Option <Vec <u8>> {
+	s.as_bytes ().chunks (2)
+	.map (|pair| match pair {
+		[hi, lo] => Some ((hex_digit (*hi)? << 4) | hex_digit (*lo)?),
+		_ => None,
+	})
+	.collect ()
+}
+
+fn hex_digit (c: u8) -> Option <u8> {
+	(c as char).to_digit (16).map (|d| d as u8)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn tokens () {
+		let csrf = Csrf::new ().unwrap ();
+		let origin = Some ("https://example.com");
+		
+		let issued = csrf.issue (&HeaderMap::new (), "/codepong/", true).unwrap ();
+		let set_cookie = issued.set_cookie.unwrap ();
+		assert! (set_cookie.ends_with ("; Path=/codepong/; HttpOnly; SameSite=Strict; Secure"));
+		
+		let mut headers = HeaderMap::new ();
+		let cookie = set_cookie.split (';').next ().unwrap ();
+		headers.insert ("cookie", format! ("theme=dark; {}", cookie).parse ().unwrap ());
+		headers.insert ("origin", "https://example.com".parse ().unwrap ());
+		
+		// Same session, same token, no new cookie
+		let again = csrf.issue (&headers, "/codepong/", true).unwrap ();
+		assert_eq! (again.token, issued.token);
+		assert! (again.set_cookie.is_none ());
+		
+		assert! (csrf.verify (&headers, origin, &issued.token).is_ok ());
+		assert! (csrf.verify (&headers, origin, "").is_err ());
+		assert! (csrf.verify (&headers, origin, "zz").is_err ());
+		assert! (csrf.verify (&headers, origin, &"0".repeat (64)).is_err ());
+		
+		// A token from another server or session doesn't work
+		let other = Csrf::new ().unwrap ();
+		let other_token = other.issue (&headers, "/", true).unwrap ().token;
+		assert! (csrf.verify (&headers, origin, &other_token).is_err ());
+		
+		let mut no_cookie = headers.clone ();
+		no_cookie.remove ("cookie");
+		assert! (csrf.verify (&no_cookie, origin, &issued.token).is_err ());
+	}
+	
+	#[test]
+	fn origins () {
+		let expected = Some ("https://example.com");
+		
+		for (name, value, ok) in [
+			("origin", "https://example.com", true),
+			("origin", "https://evil.example", false),
+			("origin", "null", false),
+			("origin", "http://example.com", false),
+			("referer", "https://example.com/codepong/next", true),
+			("referer", "https://example.com", true),
+			("referer", "https://example.com.evil.example/next", false),
+		] {
+			let mut headers = HeaderMap::new ();
+			headers.insert (name, value.parse ().unwrap ());
+			assert_eq! (same_origin (&headers, expected), ok, "{}: {}", name, value);
+		}
+		
+		assert! (same_origin (&HeaderMap::new (), expected));
+	}
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+use hyper::StatusCode;
+
+use crate::safe_path::UnsafePath;
+
+/// What handlers return when they can't produce a normal response.
+/// Everything except `Internal` is the client's fault and is shown to them
+/// as-is, `Internal` is logged and replaced with a generic message.
+#[derive (Debug)]
+pub enum Error {
+	BadRequest (String),
+	Forbidden (String),
+	NotFound (String),
+	MethodNotAllowed,
+	PayloadTooLarge (String),
+	
+	/// Seconds until the client may try again
+	TooManyRequests (u64),
+	
+	Internal (anyhow::Error),
+}
+
+impl Error {
+	pub fn bad_request <S: Into <String>> (msg: S) -> Self {
+		Self::BadRequest (msg.into ())
+	}
+	
+	pub fn forbidden <S: Into <String>> (msg: S) -> Self {
+		Self::Forbidden (msg.into ())
+	}
+	
+	pub fn not_found <S: Into <String>> (msg: S) -> Self {
+		Self::NotFound (msg.into ())
+	}
+	
+	pub fn payload_too_large <S: Into <String>> (msg: S) -> Self {
+		Self::PayloadTooLarge (msg.into ())
+	}
+	
+	pub fn status_code (&self) -> StatusCode {
+		match self {
+			Self::BadRequest (_) => StatusCode::BAD_REQUEST,
+			Self::Forbidden (_) => StatusCode::FORBIDDEN,
+			Self::NotFound (_) => StatusCode::NOT_FOUND,
+			Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+			Self::PayloadTooLarge (_) => StatusCode::PAYLOAD_TOO_LARGE,
+			Self::TooManyRequests (_) => StatusCode::TOO_MANY_REQUESTS,
+			Self::Internal (_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+	
+	/// The part that's safe to show to the client
+	pub fn message (&self) -> &str {
+		match self {
+			Self::BadRequest (s) => s,
+			Self::Forbidden (s) => s,
+			Self::NotFound (s) => s,
+			Self::MethodNotAllowed => "That method isn't allowed here.",
+			Self::PayloadTooLarge (s) => s,
+			Self::TooManyRequests (_) => "Slow down! Try again in a little while.",
+			Self::Internal (_) => "Something went wrong on the server.",
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt (&self, f: &mut fmt::Formatter <'_>) -> fmt::Result {
+		match self {
+			Self::Internal (e) => write! (f, "{:?}", e),
+			_ => write! (f, "{}: {}", self.status_code (), self.message ()),
+		}
+	}
+}
+
+impl <E: Into <anyhow::Error>> From <E> for Error {
+	fn from (e: E) -> Self {
+		Self::Internal (e.into ())
+	}
+}
+
+impl From <UnsafePath> for Error {
+	fn from (e: UnsafePath) -> Self {
+		match e {
+			UnsafePath::NotFound => Self::NotFound (e.to_string ()),
+			e => Self::BadRequest (e.to_string ()),
+		}
+	}
+}
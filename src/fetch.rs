@@ -0,0 +1,355 @@
+use std::{
+	cell::Cell,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	path::{Path, PathBuf},
+	sync::{
+		Arc,
+		atomic::{AtomicBool, AtomicU64, Ordering},
+	},
+	time::{Duration, Instant},
+};
+
+use git2::Repository;
+use hyper::Uri;
+
+use crate::{
+	config,
+	error::Error,
+};
+
+/// Fetched commits get a ref of their own until they're checked, so two
+/// fetches at once can't mix up each other's `FETCH_HEAD`
+const INCOMING_REF_PREFIX: &str = "refs/codepong/incoming/";
+
+/// A player's remote that passed the URL checks
+#[derive (Debug, PartialEq)]
+pub struct Remote {
+	url: String,
+	host: String,
+	port: u16,
+}
+
+/// Checks the shape of a remote URL and the host allowlist, before we
+/// go anywhere near the remote
+pub fn parse_url (url: &str, config: &config::Fetch) -> Result <Remote, Error> {
+	let uri: Uri = url.parse ()
+	.map_err (|_| Error::bad_request ("That isn't a valid Git remote URL"))?;
+	
+	let port = match uri.scheme_str () {
+		Some ("https") => 443,
+		Some ("ssh") => 22,
+		_ => return Err (Error::bad_request ("Git remote URL must use HTTPS or SSH protocol")),
+	};
+	
+	let authority = uri.authority ()
+	.ok_or_else (|| Error::bad_request ("Git remote URL must have a host"))?;
+	let host = authority.host ()
+	.trim_start_matches ('[')
+	.trim_end_matches (']')
+	.to_ascii_lowercase ();
+	let port = authority.port_u16 ().unwrap_or (port);
+	
+	if ! config.allowed_hosts.is_empty () && ! config.allowed_hosts.iter ().any (|h| h.eq_ignore_ascii_case (&host)) {
+		return Err (Error::bad_request (format! ("Fetching from {} isn't allowed here. Allowed hosts: {}", host, config.allowed_hosts.join (", "))));
+	}
+	
+	Ok (Remote {
+		url: url.to_string (),
+		host,
+		port,
+	})
+}
+
+/// Fetches `branch` from a remote into the repo, without holding any of
+/// our locks, and returns the commit it points to.
+///
+/// The remote's addresses are checked first, so players can't point us
+/// at the local network, and HTTP redirects aren't followed, since they
+/// could go anywhere. libgit2 does its own DNS lookup afterwards, so a
+/// remote that changes its DNS answer in between could still get past.
+pub async fn fetch (repo_path: &Path, remote: Remote, branch: &str, config: &config::Fetch) -> Result <git2::Oid, Error> {
+	if ! config.allow_private_addresses {
+		check_addresses (&remote).await?;
+	}
+	
+	let timeout = Duration::from_secs (config.timeout_secs);
+	let max_bytes = config.max_bytes;
+	let repo_path = repo_path.to_path_buf ();
+	let branch = branch.to_string ();
+	let cancel = Arc::new (AtomicBool::new (false));
+	
+	let task = {
+		let cancel = Arc::clone (&cancel);
+		tokio::task::spawn_blocking (move || fetch_blocking (repo_path, &remote.url, &branch, timeout, max_bytes, &cancel))
+	};
+	
+	// The callbacks only run while data is moving, so a remote that
+	// stalls completely is caught here instead. The fetch thread can't be
+	// interrupted, but its callbacks see `cancel` and give up as soon as
+	// the remote sends anything.
+	match tokio::time::timeout (timeout + Duration::from_secs (5), task).await {
+		Err (_) => {
+			cancel.store (true, Ordering::Relaxed);
+			Err (too_slow (timeout))
+		},
+		Ok (joined) => joined.map_err (anyhow::Error::from)?,
+	}
+}
+
+#[derive (Clone, Copy)]
+enum Stop {
+	TooBig,
+	TooSlow,
+}
+
+/// A fresh ref name in the quarantine namespace
+fn incoming_ref () -> String {
+	static COUNT: AtomicU64 = AtomicU64::new (0);
+	format! ("{}{}-{}", INCOMING_REF_PREFIX, std::process::id (), COUNT.fetch_add (1, Ordering::Relaxed))
+}
+
+fn fetch_blocking (repo_path: PathBuf, url: &str, branch: &str, timeout: Duration, max_bytes: u64, cancel: &AtomicBool) -> Result <git2::Oid, Error> {
+	let deadline = Instant::now () + timeout;
+	let repo = Repository::open (repo_path)?;
+	let refname = incoming_ref ();
+	let stop = Cell::new (None);
+	let too_late = || {
+		if Instant::now () > deadline || cancel.load (Ordering::Relaxed) {
+			stop.set (Some (Stop::TooSlow));
+			return true;
+		}
+		false
+	};
+	
+	let result = {
+		let mut callbacks = git2::RemoteCallbacks::new ();
+		callbacks.transfer_progress (|progress| {
+			if progress.received_bytes () as u64 > max_bytes {
+				stop.set (Some (Stop::TooBig));
+				return false;
+			}
+			! too_late ()
+		});
+		callbacks.sideband_progress (|_| ! too_late ());
+		
+		let mut options = git2::FetchOptions::new ();
+		options.remote_callbacks (callbacks)
+		.download_tags (git2::AutotagOption::None)
+		// Our address checks only saw the host the player gave us
+		.follow_redirects (git2::RemoteRedirect::None);
+		
+		let mut remote = repo.remote_anonymous (url)?;
+		remote.fetch (&[&format! ("+refs/heads/{}:{}", branch, refname)], Some (&mut options), None)
+	};
+	
+	match (result, stop.get ()) {
+		(_, Some (Stop::TooBig)) => return Err (Error::bad_request (format! ("That remote sent more than {} MB, which is too much", max_bytes / 1_000_000))),
+		(_, Some (Stop::TooSlow)) => return Err (too_slow (timeout)),
+		(Err (e), None) => return Err (Error::bad_request (format! ("Couldn't fetch `{}` from that remote: {}", branch, e.message ()))),
+		(Ok (()), None) => (),
+	}
+	
+	let oid = repo.refname_to_id (&refname)?;
+	repo.find_reference (&refname)?.delete ()?;
+	
+	Ok (oid)
+}
+
+fn too_slow (timeout: Duration) -> Error {
+	Error::bad_request (format! ("Fetching from that remote took longer than {} seconds", timeout.as_secs ()))
+}
+
+async fn check_addresses (remote: &Remote) -> Result <(), Error> {
+	let addrs: Vec <_> = tokio::net::lookup_host ((remote.host.as_str (), remote.port)).await
+	.map_err (|_| Error::bad_request (format! ("Can't find the address of {}", remote.host)))?
+	.collect ();
+	
+	if addrs.is_empty () {
+		return Err (Error::bad_request (format! ("Can't find the address of {}", remote.host)));
+	}
+	
+	if addrs.iter ().any (|addr| ! is_public (addr.ip ())) {
+		return Err (Error::bad_request (format! ("{} is on a private network", remote.host)));
+	}
+	
+	Ok (())
+}
+
+/// False for loopback, private, link-local, and other addresses that
+/// aren't on the public internet
+fn is_public (ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4 (v4) => is_public_v4 (v4),
+		IpAddr::V6 (v6) => match v6.to_ipv4_mapped () {
+			Some (v4) => is_public_v4 (v4),
+			None => is_public_v6 (v6),
+		},
+	}
+}
+
+fn is_public_v4 (ip: Ipv4Addr) -> bool {
+	let [a, b, c, _] = ip.octets ();
+	
+	! (
+		ip.is_private () ||
+		ip.is_loopback () ||
+		ip.is_link_local () ||
+		ip.is_broadcast () ||
+		ip.is_documentation () ||
+		ip.is_unspecified () ||
+		ip.is_multicast () ||
+		// "This network"
+		a == 0 ||
+		// Carrier-grade NAT
+		(a == 100 && (b & 0xc0) == 64) ||
+		// IETF protocol assignments
+		(a == 192 && b == 0 && c == 0) ||
+		// Benchmarking
+		(a == 198 && (b & 0xfe) == 18) ||
+		// Reserved
+		a >= 240
+	)
+}
+
+fn is_public_v6 (ip: Ipv6Addr) -> bool {
+	let s = ip.segments ();
+	
+	// 6to4 has an IPv4 address inside
+	if s [0] == 0x2002 {
+		let [a, b] = s [1].to_be_bytes ();
+		let [c, d] = s [2].to_be_bytes ();
+		return is_public_v4 (Ipv4Addr::new (a, b, c, d));
+	}
+	
+	! (
+		ip.is_loopback () ||
+		ip.is_unspecified () ||
+		ip.is_multicast () ||
+		// Unique local
+		(s [0] & 0xfe00) == 0xfc00 ||
+		// Link-local
+		(s [0] & 0xffc0) == 0xfe80 ||
+		// Documentation
+		(s [0] == 0x2001 && s [1] == 0x0db8) ||
+		// IPv4-compatible, deprecated
+		ip.to_ipv4 ().is_some ()
+	)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn urls () {
+		let open = config::Fetch::default ();
+		let strict = config::Fetch {
+			allowed_hosts: vec! ["github.com".to_string ()],
+			..Default::default ()
+		};
+		
+		assert_eq! (parse_url ("https://github.com/alice/pong.git", &strict).unwrap (), Remote {
+			url: "https://github.com/alice/pong.git".to_string (),
+			host: "github.com".to_string (),
+			port: 443,
+		});
+		assert_eq! (parse_url ("ssh://git@GitHub.com:2222/alice/pong.git", &strict).unwrap ().port, 2222);
+		assert_eq! (parse_url ("https://[::1]/x.git", &open).unwrap ().host, "::1");
+		
+		for (url, config) in [
+			("https://gitlab.com/alice/pong.git", &strict),
+			("https://github.com.evil.example/alice/pong.git", &strict),
+			("http://github.com/alice/pong.git", &open),
+			("file:///etc", &open),
+			("git@github.com:alice/pong.git", &open),
+			("/home/alice/pong", &open),
+			("ext::sh -c touch% /tmp/pwned", &open),
+		] {
+			assert! (parse_url (url, config).is_err (), "{}", url);
+		}
+	}
+	
+	#[test]
+	fn public_addresses () {
+		for (ip, public) in [
+			("140.82.112.3", true),
+			("2606:4700::1111", true),
+			("127.0.0.1", false),
+			("10.1.2.3", false),
+			("172.16.0.1", false),
+			("192.168.1.1", false),
+			("169.254.169.254", false),
+			("100.64.0.1", false),
+			("0.0.0.0", false),
+			("255.255.255.255", false),
+			("::1", false),
+			("::", false),
+			("fd00::1", false),
+			("fe80::1", false),
+			("::ffff:127.0.0.1", false),
+			("::ffff:140.82.112.3", true),
+			("2002:7f00:1::", false),
+			("2002:8c52:7003::", true),
+		] {
+			assert_eq! (is_public (ip.parse ().unwrap ()), public, "{}", ip);
+		}
+	}
+	
+	#[test]
+	fn fetching () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let sig = git2::Signature::now ("alice", "alice@example.com").unwrap ();
+		
+		let make_repo = |name: &str, content: &[u8]| {
+			let repo = Repository::init (dir.path ().join (name)).unwrap ();
+			let blob = repo.blob (content).unwrap ();
+			let mut tree = repo.treebuilder (None).unwrap ();
+			tree.insert ("game.html", blob, 0o100644).unwrap ();
+			let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+			repo.commit (Some ("refs/heads/main"), &sig, &sig, "Start", &tree, &[]).unwrap ()
+		};
+		
+		let theirs = make_repo ("theirs", &vec! [b'x'; 100_000]);
+		make_repo ("ours", b"<p>Pong</p>");
+		let url = dir.path ().join ("theirs").to_str ().unwrap ().to_string ();
+		let ours = dir.path ().join ("ours");
+		let timeout = Duration::from_secs (30);
+		let fetch_blocking = |ours, url: &str, cancel| fetch_blocking (ours, url, "main", timeout, 10_000_000, &AtomicBool::new (cancel));
+		
+		assert_eq! (fetch_blocking (ours.clone (), &url, false).unwrap (), theirs);
+		
+		// Nothing left behind
+		let repo = Repository::open (&ours).unwrap ();
+		assert! (repo.references_glob ("refs/codepong/*").unwrap ().next ().is_none ());
+		
+		assert! (fetch_blocking (ours, &format! ("{}/nope", url), false).is_err ());
+		
+		// A fetch that's been given up on stops at its next callback
+		make_repo ("fresh", b"<p>Pong</p>");
+		let err = fetch_blocking (dir.path ().join ("fresh"), &url, true).unwrap_err ();
+		assert! (err.to_string ().contains ("took longer"), "{}", err);
+	}
+	
+	#[test]
+	fn redirects () {
+		use std::io::{Read, Write};
+		
+		let dir = tempfile::tempdir ().unwrap ();
+		Repository::init (dir.path ()).unwrap ();
+		
+		// A remote that sends everyone somewhere else
+		let listener = std::net::TcpListener::bind ("127.0.0.1:0").unwrap ();
+		let port = listener.local_addr ().unwrap ().port ();
+		std::thread::spawn (move || {
+			for stream in listener.incoming ().take (2) {
+				let mut stream = stream.unwrap ();
+				let _ = stream.read (&mut [0; 4096]);
+				let _ = stream.write_all (b"HTTP/1.1 302 Found\r\nLocation: http://10.0.0.1/pong.git/info/refs?service=git-upload-pack\r\nContent-Length: 0\r\n\r\n");
+			}
+		});
+		
+		let url = format! ("http://127.0.0.1:{}/pong.git", port);
+		let err = fetch_blocking (dir.path ().to_path_buf (), &url, "main", Duration::from_secs (30), 10_000_000, &AtomicBool::new (false)).unwrap_err ();
+		assert! (err.to_string ().contains ("redirect"), "{}", err);
+	}
+}
@@ -0,0 +1,123 @@
+use std::{
+	fs,
+	path::Path,
+};
+
+use anyhow::{
+	Context,
+	bail,
+};
+use git2::{
+	Oid,
+	Repository,
+};
+
+use crate::{
+	config,
+	error::Error,
+};
+
+/// Gets the canonical repo ready at startup. It's bare, so there's no
+/// working tree to race with, and every ref update gets a reflog entry.
+/// `HEAD` points to the main branch, and side branches that don't exist
+/// yet start from it.
+///
+/// Servers from before this kept a normal clone at `legacy`. Its `.git`
+/// is moved over once, and the old working tree is left alone.
+pub fn prepare (path: &Path, legacy: &Path, branches: &config::Branches) -> anyhow::Result <()> {
+	let legacy_git = legacy.join (".git");
+	
+	if ! path.exists () && legacy_git.is_dir () {
+		fs::rename (&legacy_git, path)
+		.with_context (|| format! ("Can't move {} to {}", legacy_git.display (), path.display ()))?;
+		tracing::info! ("Moved the game repo to {}. Nothing uses {} anymore, so it can be deleted.", path.display (), legacy.display ());
+	}
+	
+	if ! path.exists () {
+		bail! ("There's no game repo at {}. Make one with `git init --bare -b main {}` and push the game to it.", path.display (), path.display ());
+	}
+	
+	let repo = Repository::open (path)?;
+	let mut config = repo.config ()?;
+	config.set_bool ("core.bare", true)?;
+	config.set_bool ("core.logAllRefUpdates", true)?;
+	
+	let main = format! ("refs/heads/{}", branches.main);
+	repo.set_head (&main)?;
+	
+	let tip = match repo.refname_to_id (&main) {
+		Ok (x) => x,
+		Err (_) => {
+			tracing::warn! ("The game repo has no `{}` branch yet", branches.main);
+			return Ok (());
+		},
+	};
+	
+	for side in &branches.side {
+		let refname = format! ("refs/heads/{}", side);
+		if repo.refname_to_id (&refname).is_err () {
+			repo.reference (&refname, tip, false, &format! ("codepong: start side branch from {}", branches.main))?;
+			tracing::info! ("Started side branch `{}` from `{}`", side, branches.main);
+		}
+	}
+	
+	Ok (())
+}
+
+/// Moves `branch` from `old` to `new`, as long as nobody else moved it
+/// first
+pub fn advance (repo: &Repository, branch: &str, old: Oid, new: Oid, log_message: &str) -> Result <(), Error> {
+	match repo.reference_matching (&format! ("refs/heads/{}", branch), new, true, old, log_message) {
+		Err (e) if e.code () == git2::ErrorCode::Modified => Err (Error::bad_request (format! ("`{}` moved while your commit was being checked. Try again.", branch))),
+		x => x.map (|_| ()).map_err (Error::from),
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn migrating () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let legacy = dir.path ().join ("repo");
+		let path = dir.path ().join ("repo.git");
+		let sig = git2::Signature::now ("alice", "alice@example.com").unwrap ();
+		let branches = config::Branches {
+			main: "main".to_string (),
+			side: vec! ["hard-mode".to_string ()],
+		};
+		
+		assert! (prepare (&path, &legacy, &branches).is_err ());
+		
+		let (start, next) = {
+			let repo = Repository::init (&legacy).unwrap ();
+			let tree = repo.find_tree (repo.treebuilder (None).unwrap ().write ().unwrap ()).unwrap ();
+			let start = repo.commit (Some ("refs/heads/main"), &sig, &sig, "Start", &tree, &[]).unwrap ();
+			let parent = repo.find_commit (start).unwrap ();
+			let next = repo.commit (None, &sig, &sig, "Next", &tree, &[&parent]).unwrap ();
+			repo.set_head ("refs/heads/master").unwrap ();
+			(start, next)
+		};
+		
+		prepare (&path, &legacy, &branches).unwrap ();
+		assert! (! legacy.join (".git").exists ());
+		
+		// Twice is fine
+		prepare (&path, &legacy, &branches).unwrap ();
+		
+		let repo = Repository::open (&path).unwrap ();
+		assert! (repo.is_bare ());
+		assert_eq! (repo.head ().unwrap ().target (), Some (start));
+		assert_eq! (repo.refname_to_id ("refs/heads/hard-mode").unwrap (), start);
+		
+		// Someone else got there first
+		assert! (advance (&repo, "main", next, start, "codepong: nope").is_err ());
+		
+		advance (&repo, "main", start, next, "codepong: commit by alice").unwrap ();
+		assert_eq! (repo.head ().unwrap ().target (), Some (next));
+		assert_eq! (repo.refname_to_id ("refs/heads/hard-mode").unwrap (), start);
+		let reflog = repo.reflog ("refs/heads/main").unwrap ();
+		assert_eq! (reflog.get (0).unwrap ().message (), Some ("codepong: commit by alice"));
+	}
+}
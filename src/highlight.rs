@@ -0,0 +1,341 @@
+use std::path::Path;
+
+/// Bigger files are only offered raw. Nobody reads those in a browser,
+/// and they'd make a huge page.
+pub const MAX_BYTES: usize = 1_000_000;
+
+/// What the source view knows how to highlight. It only picks out
+/// comments, strings, numbers and keywords, which is most of what helps
+/// when reading a small game.
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+	Css,
+	Glsl,
+	Html,
+	JavaScript,
+	Markdown,
+	Plain,
+}
+
+impl Language {
+	pub fn from_path (path: &Path) -> Self {
+		let ext = path.extension ()
+		.and_then (|ext| ext.to_str ())
+		.map (|ext| ext.to_ascii_lowercase ());
+		
+		match ext.as_deref () {
+			Some ("css") => Self::Css,
+			Some ("frag" | "glsl" | "vert") => Self::Glsl,
+			Some ("htm" | "html") => Self::Html,
+			Some ("js" | "json" | "mjs") => Self::JavaScript,
+			Some ("markdown" | "md") => Self::Markdown,
+			_ => Self::Plain,
+		}
+	}
+	
+	pub fn name (self) -> &'static str {
+		match self {
+			Self::Css => "CSS",
+			Self::Glsl => "GLSL",
+			Self::Html => "HTML",
+			Self::JavaScript => "JavaScript",
+			Self::Markdown => "Markdown",
+			Self::Plain => "Plain text",
+		}
+	}
+}
+
+const JS_KEYWORDS: &[&str] = &[
+	"async", "await", "break", "case", "catch", "class", "const", "continue",
+	"debugger", "default", "delete", "do", "else", "export", "extends",
+	"false", "finally", "for", "function", "if", "import", "in",
+	"instanceof", "let", "new", "null", "of", "return", "static", "super",
+	"switch", "this", "throw", "true", "try", "typeof", "undefined", "var",
+	"void", "while", "yield",
+];
+
+const GLSL_KEYWORDS: &[&str] = &[
+	"attribute", "bool", "break", "bvec2", "bvec3", "bvec4", "const",
+	"continue", "discard", "do", "else", "false", "float", "for", "highp",
+	"if", "in", "inout", "int", "ivec2", "ivec3", "ivec4", "layout",
+	"lowp", "mat2", "mat3", "mat4", "mediump", "out", "precision",
+	"return", "sampler2D", "samplerCube", "struct", "true", "uniform",
+	"varying", "vec2", "vec3", "vec4", "void", "while",
+];
+
+/// A run of source text and its CSS class, if it has one
+type Token <'a> = (Option <&'static str>, &'a str);
+
+/// Highlights `source` as HTML, one string per line. Spans that would
+/// cross a line break, like block comments, are closed at the end of
+/// each line and reopened on the next, so every line stands alone.
+pub fn highlight (source: &str, language: Language) -> Vec <String> {
+	let source = source.replace ("\r\n", "\n");
+	
+	let mut tokens = vec! [];
+	match language {
+		Language::Html => html (&source, &mut tokens),
+		Language::Markdown | Language::Plain => tokens.push ((None, &source [..])),
+		_ => code (&source, language, &mut tokens),
+	}
+	
+	let mut lines = vec! [String::new ()];
+	for (class, text) in tokens {
+		for (i, piece) in text.split ('\n').enumerate () {
+			if i > 0 {
+				lines.push (String::new ());
+			}
+			if piece.is_empty () {
+				continue;
+			}
+			
+			let line = lines.last_mut ().unwrap ();
+			let escaped = handlebars::html_escape (piece);
+			match class {
+				Some (class) => line.push_str (&format! ("<span class=\"hl_{}\">{}</span>", class, escaped)),
+				None => line.push_str (&escaped),
+			}
+		}
+	}
+	
+	// A trailing newline ends the last line, it doesn't start another
+	if source.ends_with ('\n') {
+		lines.pop ();
+	}
+	
+	lines
+}
+
+/// Adds a highlighted token, and the plain text since the last one
+fn push <'a> (out: &mut Vec <Token <'a>>, src: &'a str, plain: &mut usize, start: usize, end: usize, class: &'static str) {
+	if *plain < start {
+		out.push ((None, &src [*plain..start]));
+	}
+	out.push ((Some (class), &src [start..end]));
+	*plain = end;
+}
+
+/// JavaScript, GLSL and CSS, which all have C-style comments.
+///
+/// Tokens only ever start and end on ASCII bytes, so slicing `src` at
+/// them can't split a character.
+fn code <'a> (src: &'a str, language: Language, out: &mut Vec <Token <'a>>) {
+	let b = src.as_bytes ();
+	let keywords = match language {
+		Language::Glsl => GLSL_KEYWORDS,
+		Language::JavaScript => JS_KEYWORDS,
+		_ => &[],
+	};
+	let css = language == Language::Css;
+	
+	let line_end = |i: usize| src [i..].find ('\n').map (|n| i + n).unwrap_or (b.len ());
+	let next = |i: usize| b.get (i + 1).copied ().unwrap_or (0);
+	let is_ident = |c: u8| c.is_ascii_alphanumeric () || c == b'_' || c == b'$' || c >= 0x80 || (css && c == b'-');
+	
+	let mut plain = 0;
+	let mut i = 0;
+	// CSS properties are only inside braces
+	let mut depth = 0_usize;
+	
+	while i < b.len () {
+		let start = i;
+		let c = b [i];
+		
+		let class = if c == b'/' && next (i) == b'/' && ! css {
+			i = line_end (i);
+			"comment"
+		}
+		else if c == b'/' && next (i) == b'*' {
+			i = src [i + 2..].find ("*/").map (|n| i + 2 + n + 2).unwrap_or (b.len ());
+			"comment"
+		}
+		else if c == b'"' || c == b'\'' || (c == b'`' && language == Language::JavaScript) {
+			i = end_of_string (b, i);
+			"string"
+		}
+		else if c == b'#' && language == Language::Glsl && src [..i].rsplit ('\n').next ().unwrap_or ("").trim ().is_empty () {
+			i = line_end (i);
+			"meta"
+		}
+		else if c.is_ascii_digit () || (c == b'.' && next (i).is_ascii_digit ()) || (css && c == b'#' && depth > 0) {
+			i += 1;
+			while i < b.len () && (b [i].is_ascii_alphanumeric () || b"._%".contains (&b [i])) {
+				i += 1;
+			}
+			"number"
+		}
+		else if c.is_ascii_alphabetic () || c == b'_' || c == b'$' || (css && (c == b'@' || (c == b'-' && ! next (i).is_ascii_digit ()))) {
+			i += 1;
+			while i < b.len () && is_ident (b [i]) {
+				i += 1;
+			}
+			
+			let word = &src [start..i];
+			if css && c == b'@' {
+				"keyword"
+			}
+			else if css && depth > 0 && src [i..].trim_start ().starts_with (':') {
+				"property"
+			}
+			else if keywords.contains (&word) {
+				"keyword"
+			}
+			else {
+				continue;
+			}
+		}
+		else {
+			match c {
+				b'{' => depth += 1,
+				b'}' => depth = depth.saturating_sub (1),
+				_ => (),
+			}
+			i += 1;
+			continue;
+		};
+		
+		push (out, src, &mut plain, start, i, class);
+	}
+	
+	if plain < b.len () {
+		out.push ((None, &src [plain..]));
+	}
+}
+
+/// Where a string starting at `start` ends, just past its closing quote.
+/// Unclosed strings end at the line break, except template literals.
+fn end_of_string (b: &[u8], start: usize) -> usize {
+	let quote = b [start];
+	let mut i = start + 1;
+	
+	while i < b.len () {
+		match b [i] {
+			b'\\' => i += 2,
+			c if c == quote => return i + 1,
+			b'\n' if quote != b'`' => return i,
+			_ => i += 1,
+		}
+	}
+	
+	b.len ()
+}
+
+/// Tags, attributes and comments, with `<script>` and `<style>` contents
+/// highlighted as what they are. Like in `code`, tokens start and end on
+/// ASCII bytes, and the checks between them look at bytes, since `i` can
+/// be in the middle of a character there.
+fn html <'a> (src: &'a str, out: &mut Vec <Token <'a>>) {
+	let b = src.as_bytes ();
+	let mut plain = 0;
+	let mut i = 0;
+	
+	while i < b.len () {
+		let start = i;
+		
+		if b [i..].starts_with (b"<!--") {
+			i = src [i..].find ("-->").map (|n| i + n + 3).unwrap_or (b.len ());
+			push (out, src, &mut plain, start, i, "comment");
+		}
+		else if b [i] == b'<' && b.get (i + 1).map (|c| c.is_ascii_alphabetic () || b"/!".contains (c)).unwrap_or (false) {
+			i += 2;
+			while i < b.len () && (b [i].is_ascii_alphanumeric () || b [i] == b'-') {
+				i += 1;
+			}
+			let name = src [start..i].to_ascii_lowercase ();
+			push (out, src, &mut plain, start, i, "tag");
+			
+			while i < b.len () && b [i] != b'>' {
+				let attr_start = i;
+				if b [i] == b'"' || b [i] == b'\'' {
+					i = src [i + 1..].find (b [i] as char).map (|n| i + 1 + n + 1).unwrap_or (b.len ());
+					push (out, src, &mut plain, attr_start, i, "string");
+				}
+				else if b [i].is_ascii_alphabetic () {
+					while i < b.len () && (b [i].is_ascii_alphanumeric () || b"-_:".contains (&b [i])) {
+						i += 1;
+					}
+					push (out, src, &mut plain, attr_start, i, "attr");
+				}
+				else {
+					i += 1;
+				}
+			}
+			
+			let tag = src [start..i].to_ascii_lowercase ();
+			if i < b.len () {
+				push (out, src, &mut plain, i, i + 1, "tag");
+				i += 1;
+			}
+			
+			// WebGL games keep their shaders in script tags too
+			let embedded = match name.as_str () {
+				"<script" if tag.contains ("shader") || tag.contains ("glsl") => Some ((Language::Glsl, "</script")),
+				"<script" if ! tag.contains ("type=") || tag.contains ("javascript") || tag.contains ("module") || tag.contains ("json") => Some ((Language::JavaScript, "</script")),
+				"<script" => Some ((Language::Plain, "</script")),
+				"<style" => Some ((Language::Css, "</style")),
+				_ => None,
+			};
+			
+			if let Some ((language, close)) = embedded {
+				let end = src [i..].to_ascii_lowercase ().find (close).map (|n| i + n).unwrap_or (b.len ());
+				match language {
+					Language::Plain => out.push ((None, &src [i..end])),
+					_ => code (&src [i..end], language, out),
+				}
+				i = end;
+				plain = end;
+			}
+		}
+		else {
+			i += 1;
+		}
+	}
+	
+	if plain < b.len () {
+		out.push ((None, &src [plain..]));
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn highlighting () {
+		let js = highlight ("let x = \"<b>\"; // speed\r\nx += 1.5;\n", Language::JavaScript);
+		assert_eq! (js, vec! [
+			"<span class=\"hl_keyword\">let</span> x &#x3D; <span class=\"hl_string\">&quot;&lt;b&gt;&quot;</span>; <span class=\"hl_comment\">// speed</span>",
+			"x +&#x3D; <span class=\"hl_number\">1.5</span>;",
+		]);
+		
+		// Spans don't cross lines
+		let js = highlight ("/* a\nb */ letter", Language::JavaScript);
+		assert_eq! (js, vec! [
+			"<span class=\"hl_comment\">/* a</span>",
+			"<span class=\"hl_comment\">b */</span> letter",
+		]);
+		
+		let glsl = highlight ("  #version 100\nvec2 a = b # c;", Language::Glsl);
+		assert_eq! (glsl [0], "  <span class=\"hl_meta\">#version 100</span>");
+		assert_eq! (glsl [1], "<span class=\"hl_keyword\">vec2</span> a &#x3D; b # c;");
+		
+		let css = highlight ("a:hover { color: #fff; }", Language::Css);
+		assert_eq! (css, vec! ["a:hover { <span class=\"hl_property\">color</span>: <span class=\"hl_number\">#fff</span>; }"]);
+		
+		// Text outside tags is stepped through a byte at a time
+		let page = highlight ("<p>Café <!-- ünïcode --></p>\n", Language::Html);
+		assert_eq! (page, vec! [
+			"<span class=\"hl_tag\">&lt;p</span><span class=\"hl_tag\">&gt;</span>Café <span class=\"hl_comment\">&lt;!-- ünïcode --&gt;</span><span class=\"hl_tag\">&lt;/p</span><span class=\"hl_tag\">&gt;</span>",
+		]);
+		
+		let page = highlight ("<p class='x'>if</p>\n<script>if (é)</script>\n<script type=\"x-shader/x-fragment\">void main</script>", Language::Html);
+		assert_eq! (page, vec! [
+			"<span class=\"hl_tag\">&lt;p</span> <span class=\"hl_attr\">class</span>&#x3D;<span class=\"hl_string\">&#x27;x&#x27;</span><span class=\"hl_tag\">&gt;</span>if<span class=\"hl_tag\">&lt;/p</span><span class=\"hl_tag\">&gt;</span>",
+			"<span class=\"hl_tag\">&lt;script</span><span class=\"hl_tag\">&gt;</span><span class=\"hl_keyword\">if</span> (é)<span class=\"hl_tag\">&lt;/script</span><span class=\"hl_tag\">&gt;</span>",
+			"<span class=\"hl_tag\">&lt;script</span> <span class=\"hl_attr\">type</span>&#x3D;<span class=\"hl_string\">&quot;x-shader/x-fragment&quot;</span><span class=\"hl_tag\">&gt;</span><span class=\"hl_keyword\">void</span> main<span class=\"hl_tag\">&lt;/script</span><span class=\"hl_tag\">&gt;</span>",
+		]);
+		
+		assert_eq! (highlight ("a < b\n\nc", Language::Plain), vec! ["a &lt; b", "", "c"]);
+		assert_eq! (highlight ("", Language::Plain), vec! [""]);
+	}
+}
@@ -0,0 +1,409 @@
+use std::path::Path;
+
+use git2::{
+	Commit,
+	Oid,
+	Repository,
+	Revwalk,
+};
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// A commit as it's shown in lists
+#[derive (Serialize)]
+pub struct CommitData {
+	#[serde (skip)]
+	pub oid: Oid,
+	pub id: String,
+	pub id_short: String,
+	pub author: Option <String>,
+	pub time: String,
+	pub message: Option <String>,
+	pub merge: bool,
+}
+
+/// One page of history
+pub struct Log {
+	pub commits: Vec <CommitData>,
+	
+	/// The cursor for the next page, if there is one
+	pub next: Option <Oid>,
+}
+
+/// Walks `branch` from its tip, up to `n` commits, starting after the
+/// commit `after`, which is the last one on the previous page.
+///
+/// The order is topological, so merges come before the commits they
+/// brought in. With `first_parent`, merged-in commits are skipped and
+/// only the branch's own turns are shown.
+pub fn log (repo: &Repository, branch: &str, after: Option <Oid>, n: usize, first_parent: bool) -> Result <Log, Error> {
+	let mut walk = repo.revwalk ()?;
+	walk.set_sorting (git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+	walk.push_ref (&format! ("refs/heads/{}", branch))
+	.map_err (|_| Error::not_found (format! ("There's no branch called `{}` here", branch)))?;
+	if first_parent {
+		walk.simplify_first_parent ()?;
+	}
+	
+	page (repo, walk, after, n, |_| Ok (true))
+}
+
+/// Like `log`, but only the commits before `start` that changed `path`
+pub fn path_log (repo: &Repository, start: Oid, path: &Path, after: Option <Oid>, n: usize) -> Result <Log, Error> {
+	let mut walk = repo.revwalk ()?;
+	walk.set_sorting (git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+	walk.push (start)?;
+	
+	page (repo, walk, after, n, |commit| touches (commit, path))
+}
+
+fn page <F> (repo: &Repository, walk: Revwalk, after: Option <Oid>, n: usize, mut filter: F) -> Result <Log, Error>
+where F: FnMut (&Commit) -> Result <bool, git2::Error>
+{
+	let mut walk = walk;
+	
+	// The cursor is a commit ID rather than an offset, so pages don't
+	// shift when someone commits in the meantime
+	if let Some (after) = after {
+		loop {
+			match walk.next ().transpose ()? {
+				Some (oid) if oid == after => break,
+				Some (_) => (),
+				None => return Err (Error::not_found (format! ("Commit {} isn't in this history", after))),
+			}
+		}
+	}
+	
+	let replacer = gh_emoji::Replacer::new ();
+	let mut commits = vec! [];
+	let mut next = None;
+	
+	for oid in walk {
+		let commit = repo.find_commit (oid?)?;
+		if ! filter (&commit)? {
+			continue;
+		}
+		
+		// One more than needed, to know if there's another page
+		if commits.len () == n {
+			next = commits.last ().map (|c: &CommitData| c.oid);
+			break;
+		}
+		
+		commits.push (commit_data (&commit, &replacer));
+	}
+	
+	Ok (Log {
+		commits,
+		next,
+	})
+}
+
+fn commit_data (commit: &Commit, replacer: &gh_emoji::Replacer) -> CommitData {
+	use chrono::{DateTime, NaiveDateTime, Utc};
+	
+	let time = commit.time ();
+	let time = DateTime::<Utc>::from_utc (NaiveDateTime::from_timestamp (time.seconds (), 0), Utc);
+	
+	let id = commit.id ().to_string ();
+	CommitData {
+		oid: commit.id (),
+		id_short: id [0..8].to_string (),
+		id,
+		author: commit.author ().name ().map (|s| s.to_string ()),
+		time: time.to_string (),
+		message: commit.message ().map (|s| replacer.replace_all (s).to_string ()),
+		merge: commit.parent_count () > 1,
+	}
+}
+
+/// The newest commit before `start` that changed each of `names` in the
+/// directory `dir`. Only the last `limit` commits are looked at, so
+/// entries nobody has touched in a long time get `None`.
+pub fn last_changes (repo: &Repository, start: Oid, dir: &Path, names: &[String], limit: usize) -> Result <Vec <Option <CommitData>>, Error> {
+	let entry_id = |tree: &Option <git2::Tree>, name: &str| {
+		tree.as_ref ().and_then (|t| t.get_name (name)).map (|e| e.id ())
+	};
+	
+	let mut walk = repo.revwalk ()?;
+	walk.set_sorting (git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+	walk.push (start)?;
+	
+	let replacer = gh_emoji::Replacer::new ();
+	let mut found: Vec <Option <CommitData>> = names.iter ().map (|_| None).collect ();
+	let mut left = names.len ();
+	
+	for oid in walk.take (limit) {
+		if left == 0 {
+			break;
+		}
+		
+		let commit = repo.find_commit (oid?)?;
+		let mine = dir_tree (repo, &commit, dir)?;
+		let parents = commit.parents ()
+		.map (|p| dir_tree (repo, &p, dir))
+		.collect::<Result <Vec <_>, _>> ()?;
+		
+		// Most commits don't touch this directory at all
+		let tree_id = mine.as_ref ().map (|t| t.id ());
+		if parents.iter ().any (|p| p.as_ref ().map (|t| t.id ()) == tree_id) {
+			continue;
+		}
+		
+		for (name, found) in names.iter ().zip (found.iter_mut ()) {
+			if found.is_some () {
+				continue;
+			}
+			
+			let id = entry_id (&mine, name);
+			if id.is_some () && parents.iter ().all (|p| entry_id (p, name) != id) {
+				*found = Some (commit_data (&commit, &replacer));
+				left -= 1;
+			}
+		}
+	}
+	
+	Ok (found)
+}
+
+/// The directory `dir` in `commit`, if it's there
+fn dir_tree <'r> (repo: &'r Repository, commit: &Commit <'r>, dir: &Path) -> Result <Option <git2::Tree <'r>>, git2::Error> {
+	let tree = commit.tree ()?;
+	if dir.as_os_str ().is_empty () {
+		return Ok (Some (tree));
+	}
+	
+	match tree.get_path (dir) {
+		Ok (entry) => Ok (entry.to_object (repo)?.into_tree ().ok ()),
+		Err (_) => Ok (None),
+	}
+}
+
+/// True if `path` in `commit` isn't the same as in any of its parents. A
+/// merge that kept one side's version didn't change it.
+fn touches (commit: &Commit, path: &Path) -> Result <bool, git2::Error> {
+	let entry_id = |commit: &Commit| -> Result <Option <Oid>, git2::Error> {
+		Ok (commit.tree ()?.get_path (path).ok ().map (|e| e.id ()))
+	};
+	
+	let mine = entry_id (commit)?;
+	if commit.parent_count () == 0 {
+		return Ok (mine.is_some ());
+	}
+	
+	for parent in commit.parents () {
+		if entry_id (&parent)? == mine {
+			return Ok (false);
+		}
+	}
+	
+	Ok (true)
+}
+
+/// One line of a blamed file
+#[derive (Serialize)]
+pub struct BlameLine {
+	pub number: usize,
+	pub text: String,
+	
+	/// Only on the first line of each run of lines from the same commit
+	pub commit: Option <BlameCommit>,
+}
+
+#[derive (Serialize)]
+pub struct BlameCommit {
+	pub id: String,
+	pub id_short: String,
+	pub author: Option <String>,
+}
+
+/// Blaming is slow on big files, and nobody reads those line by line
+const MAX_BLAME_BYTES: usize = 1_000_000;
+
+/// Which commit last changed each line of `path`, as of `commit`
+pub fn blame (repo: &Repository, commit: Oid, path: &Path) -> Result <Vec <BlameLine>, Error> {
+	let not_a_file = || Error::not_found (format! ("There's no file at `{}` in that commit", path.display ()));
+	
+	let entry = repo.find_commit (commit)?.tree ()?.get_path (path).map_err (|_| not_a_file ())?;
+	let blob = entry.to_object (repo)?.into_blob ().map_err (|_| not_a_file ())?;
+	if blob.is_binary () {
+		return Err (Error::bad_request ("Binary files can't be blamed"));
+	}
+	if blob.size () > MAX_BLAME_BYTES {
+		return Err (Error::bad_request (format! ("Files over {} MB can't be blamed", MAX_BLAME_BYTES / 1_000_000)));
+	}
+	
+	let mut options = git2::BlameOptions::new ();
+	options.newest_commit (commit);
+	let blame = repo.blame_file (path, Some (&mut options))?;
+	
+	let text = String::from_utf8_lossy (blob.content ());
+	let mut lines = vec! [];
+	
+	for (i, line) in text.lines ().enumerate () {
+		let number = i + 1;
+		let commit = blame.get_line (number)
+		.filter (|hunk| hunk.final_start_line () == number)
+		.map (|hunk| {
+			let id = hunk.final_commit_id ().to_string ();
+			BlameCommit {
+				id_short: id [0..8].to_string (),
+				id,
+				author: hunk.final_signature ().name ().map (|s| s.to_string ()),
+			}
+		});
+		
+		lines.push (BlameLine {
+			number,
+			text: line.to_string (),
+			commit,
+		});
+	}
+	
+	Ok (lines)
+}
+
+/// A commit's message and its diff against its first parent, like
+/// `git show`
+pub fn patch (repo: &Repository, commit: Oid) -> Result <String, Error> {
+	let commit = repo.find_commit (commit)?;
+	let parent_tree = match commit.parent_count () {
+		0 => None,
+		_ => Some (commit.parent (0)?.tree ()?),
+	};
+	let diff = repo.diff_tree_to_tree (parent_tree.as_ref (), Some (&commit.tree ()?), None)?;
+	
+	let author = commit.author ();
+	let mut out = format! (
+		"commit {}\nAuthor: {} <{}>\n\n{}\n\n",
+		commit.id (),
+		author.name ().unwrap_or (""),
+		author.email ().unwrap_or (""),
+		commit.message ().unwrap_or ("").trim_end (),
+	);
+	
+	diff.print (git2::DiffFormat::Patch, |_, _, line| {
+		if let '+' | '-' | ' ' = line.origin () {
+			out.push (line.origin ());
+		}
+		out.push_str (&String::from_utf8_lossy (line.content ()));
+		true
+	})?;
+	
+	Ok (out)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn paging () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let repo = Repository::init (dir.path ()).unwrap ();
+		let tree = repo.find_tree (repo.treebuilder (None).unwrap ().write ().unwrap ()).unwrap ();
+		
+		let mut time = 1_600_000_000;
+		let mut commit = |msg: &str, parents: &[Oid]| {
+			time += 60;
+			let sig = git2::Signature::new ("alice", "alice@example.com", &git2::Time::new (time, 0)).unwrap ();
+			let parents: Vec <_> = parents.iter ().map (|p| repo.find_commit (*p).unwrap ()).collect ();
+			let parents: Vec <_> = parents.iter ().collect ();
+			repo.commit (None, &sig, &sig, msg, &tree, &parents).unwrap ()
+		};
+		
+		// A turn on a side branch got merged back in
+		let start = commit ("Start", &[]);
+		let side = commit ("Side", &[start]);
+		let main = commit ("Main", &[start]);
+		let merge = commit ("Merge", &[main, side]);
+		let tip = commit ("Tip", &[merge]);
+		repo.reference ("refs/heads/main", tip, true, "").unwrap ();
+		
+		let messages = |log: &Log| log.commits.iter ().map (|c| c.message.clone ().unwrap ()).collect::<Vec <_>> ();
+		
+		// Used to stop at the merge
+		let all = log (&repo, "main", None, 10, false).unwrap ();
+		assert_eq! (all.commits.len (), 5);
+		assert! (all.commits [1].merge);
+		assert_eq! (all.commits.last ().unwrap ().id, start.to_string ());
+		assert! (all.next.is_none ());
+		
+		let turns = log (&repo, "main", None, 10, true).unwrap ();
+		assert_eq! (messages (&turns), vec! ["Tip", "Merge", "Main", "Start"]);
+		
+		let page_1 = log (&repo, "main", None, 2, false).unwrap ();
+		assert_eq! (messages (&page_1), vec! ["Tip", "Merge"]);
+		assert_eq! (page_1.next, Some (merge));
+		
+		let page_2 = log (&repo, "main", page_1.next, 2, false).unwrap ();
+		let page_3 = log (&repo, "main", page_2.next, 2, false).unwrap ();
+		assert_eq! (page_2.commits.len () + page_3.commits.len (), 3);
+		assert_eq! (messages (&page_3).last ().unwrap (), "Start");
+		assert! (page_3.next.is_none ());
+		
+		assert! (matches! (log (&repo, "main", Some (Oid::zero ()), 2, false), Err (Error::NotFound (_))));
+		assert! (matches! (log (&repo, "nope", None, 2, false), Err (Error::NotFound (_))));
+	}
+	
+	#[test]
+	fn file_history () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let repo = Repository::init (dir.path ()).unwrap ();
+		
+		let mut time = 1_600_000_000;
+		let mut commit = |author: &str, files: &[(&str, &str)], parents: &[Oid]| {
+			time += 60;
+			let sig = git2::Signature::new (author, "player@example.com", &git2::Time::new (time, 0)).unwrap ();
+			let mut tree = repo.treebuilder (None).unwrap ();
+			for (name, content) in files {
+				tree.insert (name, repo.blob (content.as_bytes ()).unwrap (), 0o100644).unwrap ();
+			}
+			let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+			let parents: Vec <_> = parents.iter ().map (|p| repo.find_commit (*p).unwrap ()).collect ();
+			let parents: Vec <_> = parents.iter ().collect ();
+			repo.commit (None, &sig, &sig, author, &tree, &parents).unwrap ()
+		};
+		
+		let start = commit ("alice", &[("game.html", "<p>\nPong\n</p>\n")], &[]);
+		let readme = commit ("bob", &[("game.html", "<p>\nPong\n</p>\n"), ("README", "Hi\n")], &[start]);
+		let faster = commit ("carol", &[("game.html", "<p>\nPong, but faster\n</p>\n"), ("README", "Hi\n")], &[readme]);
+		let image = commit ("dave", &[("game.html", "<p>\nPong, but faster\n</p>\n"), ("README", "Hi\n"), ("ball.png", "\0\0")], &[faster]);
+		
+		let game = Path::new ("game.html");
+		let authors = |log: &Log| log.commits.iter ().map (|c| c.author.clone ().unwrap ()).collect::<Vec <_>> ();
+		
+		let all = path_log (&repo, image, game, None, 10).unwrap ();
+		assert_eq! (authors (&all), vec! ["carol", "alice"]);
+		assert! (all.next.is_none ());
+		
+		let page_1 = path_log (&repo, image, game, None, 1).unwrap ();
+		assert_eq! (page_1.next, Some (faster));
+		let page_2 = path_log (&repo, image, game, page_1.next, 1).unwrap ();
+		assert_eq! (authors (&page_2), vec! ["alice"]);
+		assert! (page_2.next.is_none ());
+		
+		assert_eq! (authors (&path_log (&repo, image, Path::new ("README"), None, 10).unwrap ()), vec! ["bob"]);
+		
+		let lines = blame (&repo, image, game).unwrap ();
+		let texts: Vec <_> = lines.iter ().map (|l| l.text.as_str ()).collect ();
+		assert_eq! (texts, vec! ["<p>", "Pong, but faster", "</p>"]);
+		let starts: Vec <_> = lines.iter ().map (|l| l.commit.as_ref ().and_then (|c| c.author.clone ())).collect ();
+		assert_eq! (starts, vec! [Some ("alice".to_string ()), Some ("carol".to_string ()), Some ("alice".to_string ())]);
+		
+		// As of an older commit
+		let lines = blame (&repo, start, game).unwrap ();
+		assert_eq! (lines [1].text, "Pong");
+		assert! (lines [1].commit.is_none ());
+		
+		assert! (matches! (blame (&repo, image, Path::new ("ball.png")), Err (Error::BadRequest (_))));
+		assert! (matches! (blame (&repo, start, Path::new ("README")), Err (Error::NotFound (_))));
+		
+		let diff = patch (&repo, faster).unwrap ();
+		assert! (diff.starts_with (&format! ("commit {}\nAuthor: carol <player@example.com>\n", faster)));
+		assert! (diff.contains ("-Pong\n+Pong, but faster\n"));
+		assert! (! diff.contains ("README"));
+		assert! (patch (&repo, start).unwrap ().contains ("+<p>\n"));
+	}
+}
@@ -0,0 +1,112 @@
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use hyper::{
+	Body,
+	HeaderMap,
+	Response,
+	StatusCode,
+};
+
+/// For URLs with a full commit ID in them, the content can never change
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// For everything else, caches have to check back with us every time,
+/// which is cheap thanks to ETags
+pub const REVALIDATE: &str = "no-cache";
+
+/// Strong ETag for a Git object, which is already a content hash
+pub fn git_etag (oid: git2::Oid) -> String {
+	format! ("\"{}\"", oid)
+}
+
+/// ETag for a file on disk, made from its size and mtime
+pub fn file_etag (len: u64, modified: SystemTime) -> String {
+	let nanos = modified.duration_since (SystemTime::UNIX_EPOCH)
+	.map (|d| d.as_nanos ())
+	.unwrap_or_default ();
+	
+	format! ("\"{:x}-{:x}\"", len, nanos)
+}
+
+/// Formats a time as an RFC 7231 HTTP-date, e.g. for `Last-Modified`
+pub fn http_date (t: SystemTime) -> String {
+	DateTime::<Utc>::from (t).format ("%a, %d %b %Y %H:%M:%S GMT").to_string ()
+}
+
+/// True if the client's `If-None-Match` header says it already has `etag`
+pub fn etag_matches (headers: &HeaderMap, etag: &str) -> bool {
+	let value = match headers.get ("if-none-match").and_then (|v| v.to_str ().ok ()) {
+		None => return false,
+		Some (x) => x,
+	};
+	
+	// If-None-Match uses the weak comparison
+	let etag = etag.trim_start_matches ("W/");
+	
+	value.split (',')
+	.map (|s| s.trim ())
+	.any (|s| s == "*" || s.trim_start_matches ("W/") == etag)
+}
+
+/// True if the client's `If-Modified-Since` is at or after `modified`.
+/// Ignored when the client also sent `If-None-Match`, as RFC 7232 says.
+pub fn not_modified_since (headers: &HeaderMap, modified: SystemTime) -> bool {
+	if headers.contains_key ("if-none-match") {
+		return false;
+	}
+	
+	let since = match headers.get ("if-modified-since")
+	.and_then (|v| v.to_str ().ok ())
+	.and_then (|s| DateTime::parse_from_rfc2822 (s).ok ())
+	{
+		None => return false,
+		Some (x) => x,
+	};
+	
+	// HTTP dates only have whole seconds
+	let modified = DateTime::<Utc>::from (modified).timestamp ();
+	modified <= since.timestamp ()
+}
+
+/// An empty 304 carrying the validators that a 200 would have had
+pub fn not_modified (etag: &str, cache_control: &str) -> anyhow::Result <Response <Body>> {
+	Ok (Response::builder ()
+	.status (StatusCode::NOT_MODIFIED)
+	.header ("etag", etag)
+	.header ("cache-control", cache_control)
+	.body (Body::empty ())?)
+}
+
+#[cfg (test)]
+mod tests {
+	use std::time::Duration;
+	
+	use super::*;
+	
+	fn headers (name: &'static str, value: &'static str) -> HeaderMap {
+		let mut h = HeaderMap::new ();
+		h.insert (name, value.parse ().unwrap ());
+		h
+	}
+	
+	#[test]
+	fn conditional () {
+		let etag = "\"8d5f3c1a\"";
+		
+		assert! (! etag_matches (&HeaderMap::new (), etag));
+		assert! (etag_matches (&headers ("if-none-match", "\"8d5f3c1a\""), etag));
+		assert! (etag_matches (&headers ("if-none-match", "W/\"8d5f3c1a\""), etag));
+		assert! (etag_matches (&headers ("if-none-match", "\"abc\", \"8d5f3c1a\""), etag));
+		assert! (etag_matches (&headers ("if-none-match", "*"), etag));
+		assert! (! etag_matches (&headers ("if-none-match", "\"abc\""), etag));
+		
+		let t = SystemTime::UNIX_EPOCH + Duration::from_millis (784_111_777_500);
+		assert_eq! (http_date (t), "Sun, 06 Nov 1994 08:49:37 GMT");
+		
+		let h = headers ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT");
+		assert! (not_modified_since (&h, t));
+		assert! (! not_modified_since (&h, t + Duration::from_secs (1)));
+		assert! (! not_modified_since (&headers ("if-modified-since", "yesterday"), t));
+	}
+}
@@ -0,0 +1,270 @@
+use std::{
+	collections::VecDeque,
+	time::Duration,
+};
+
+use irc::client::prelude::Command;
+use tokio::{
+	sync::mpsc,
+	time::Instant,
+};
+
+use crate::token_bucket::TokenBucket;
+
+/// Notifications that arrive within this window of the first one are
+/// coalesced into a single summary line.
+const COALESCE_WINDOW: Duration = Duration::from_secs (2);
+
+/// Token bucket settings. Most networks let a client burst a handful of
+/// lines and then expect roughly one line every couple of seconds.
+const BURST_SIZE: f64 = 4.0;
+const REFILL_PER_SECOND: f64 = 0.5;
+
+/// Lines waiting for a token. Past this the oldest are dropped, since a
+/// long backlog would only be stale news by the time it went out.
+const MAX_QUEUED_LINES: usize = 20;
+
+/// A summary names at most this many notifications and counts the rest
+const MAX_COALESCED: usize = 5;
+
+/// IRC lines are at most 512 bytes including the trailing CRLF.
+const MAX_LINE_BYTES: usize = 512;
+
+/// When the server relays our PRIVMSG to other clients it prepends
+/// `:nick!user@host `, which we can't measure from here, so leave room
+/// for a generous one.
+const SOURCE_PREFIX_RESERVE: usize = 100;
+
+enum Outgoing {
+	/// Goes to every joined channel
+	Notification (String),
+	
+	/// Answer to a command, goes to one target
+	Reply {
+		target: String,
+		text: String,
+	},
+}
+
+/// Outbound queue for one IRC connection. All PRIVMSGs go through here so
+/// that a burst of baton activity can't get the bot kicked for flooding.
+#[derive (Clone)]
+pub struct IrcOutbox {
+	tx: mpsc::UnboundedSender <Outgoing>,
+}
+
+impl IrcOutbox {
+	pub fn spawn (sender: irc::client::Sender, channels: Vec <String>) -> Self {
+		let (tx, rx) = mpsc::unbounded_channel ();
+		
+		tokio::spawn (async move {
+			run (sender, channels, rx).await;
+		});
+		
+		Self {
+			tx,
+		}
+	}
+	
+	/// An outbox that isn't connected to anything, for tests that need a
+	/// server but not IRC
+	#[cfg (test)]
+	pub fn closed () -> Self {
+		let (tx, _) = mpsc::unbounded_channel ();
+		
+		Self {
+			tx,
+		}
+	}
+	
+	pub fn notify (&self, msg: &str) -> anyhow::Result <()> {
+		self.tx.send (Outgoing::Notification (msg.to_string ()))
+		.map_err (|_| anyhow::anyhow! ("IRC outbox is closed"))
+	}
+	
+	pub fn reply (&self, target: &str, text: &str) -> anyhow::Result <()> {
+		self.tx.send (Outgoing::Reply {
+			target: target.to_string (),
+			text: text.to_string (),
+		})
+		.map_err (|_| anyhow::anyhow! ("IRC outbox is closed"))
+	}
+}
+
+async fn run (
+	sender: irc::client::Sender,
+	channels: Vec <String>,
+	mut rx: mpsc::UnboundedReceiver <Outgoing>,
+)
+{
+	let mut bucket = TokenBucket::new (BURST_SIZE, REFILL_PER_SECOND, Instant::now ());
+	let mut queue: VecDeque <(String, String)> = Default::default ();
+	let mut pending: Vec <String> = vec! [];
+	let mut pending_extra = 0;
+	let mut flush_at: Option <Instant> = None;
+	
+	loop {
+		let send_at = match queue.is_empty () {
+			true => None,
+			false => Some (Instant::now () + bucket.time_until_token (Instant::now ())),
+		};
+		
+		tokio::select! {
+			msg = rx.recv () => match msg {
+				None => break,
+				Some (Outgoing::Notification (text)) => {
+					match pending.len () < MAX_COALESCED {
+						true => pending.push (text),
+						false => pending_extra += 1,
+					}
+					flush_at.get_or_insert_with (|| Instant::now () + COALESCE_WINDOW);
+				},
+				Some (Outgoing::Reply {target, text}) => enqueue (&mut queue, &target, &text),
+			},
+			_ = tokio::time::sleep_until (flush_at.unwrap_or_else (Instant::now)), if flush_at.is_some () => {
+				let text = coalesce (&pending, pending_extra);
+				pending.clear ();
+				pending_extra = 0;
+				flush_at = None;
+				
+				for channel in &channels {
+					enqueue (&mut queue, channel, &text);
+				}
+			},
+			_ = tokio::time::sleep_until (send_at.unwrap_or_else (Instant::now)), if send_at.is_some () => {
+				if ! bucket.try_take (Instant::now ()) {
+					continue;
+				}
+				
+				if let Some ((target, line)) = queue.pop_front () {
+					if let Err (e) = sender.send (Command::PRIVMSG (target, line)) {
+						tracing::error! ("Couldn't send IRC message: {:?}", e);
+					}
+				}
+			},
+		}
+	}
+}
+
+/// Queues `text` for `target`, dropping the oldest lines if it's full
+fn enqueue (queue: &mut VecDeque <(String, String)>, target: &str, text: &str) {
+	for line in split_message (target, text) {
+		if queue.len () >= MAX_QUEUED_LINES {
+			if let Some ((_, dropped)) = queue.pop_front () {
+				tracing::warn! ("IRC outbox is full, dropped: {}", dropped);
+			}
+		}
+		queue.push_back ((target.to_string (), line));
+	}
+}
+
+/// Turns several notifications that arrived close together into one line.
+/// `extra` is how many more arrived that aren't in `events`.
+fn coalesce (events: &[String], extra: usize) -> String {
+	match (events, extra) {
+		([], _) => String::new (),
+		([one], 0) => one.clone (),
+		(_, 0) => format! ("{} updates: {}", events.len (), events.join (" | ")),
+		_ => format! ("{} updates: {} | and {} more", events.len () + extra, events.join (" | "), extra),
+	}
+}
+
+/// Splits `text` into PRIVMSG payloads that fit in one IRC line when sent
+/// to `target`. Newlines start a new message, long lines are broken at the
+/// last space that fits, or at a char boundary if there's no space, so a
+/// multi-byte UTF-8 sequence is never cut in half.
+fn split_message (target: &str, text: &str) -> Vec <String> {
+	let overhead = "PRIVMSG ".len () + target.len () + " :".len () + "\r\n".len () + SOURCE_PREFIX_RESERVE;
+	let max_bytes = MAX_LINE_BYTES.saturating_sub (overhead).max (1);
+	
+	let mut messages = vec! [];
+	
+	for line in text.split (['\r', '\n']) {
+		let mut rest = line;
+		
+		while rest.len () > max_bytes {
+			let mut end = max_bytes;
+			while ! rest.is_char_boundary (end) {
+				end -= 1;
+			}
+			
+			if end == 0 {
+				// A single char wider than the limit, only possible with
+				// an absurdly long target
+				end = rest.chars ().next ().map (|c| c.len_utf8 ()).unwrap_or (rest.len ());
+			}
+			
+			let (chunk, tail) = match rest [..end].rfind (' ') {
+				Some (space) if space > 0 => (&rest [..space], &rest [space + 1..]),
+				_ => rest.split_at (end),
+			};
+			
+			messages.push (chunk.to_string ());
+			rest = tail;
+		}
+		
+		if ! rest.is_empty () {
+			messages.push (rest.to_string ());
+		}
+	}
+	
+	messages
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn split () {
+		let limit = MAX_LINE_BYTES - "PRIVMSG #test :\r\n".len () - SOURCE_PREFIX_RESERVE;
+		
+		assert_eq! (split_message ("#test", "Hi, everybody!"), vec! ["Hi, everybody!"]);
+		assert_eq! (split_message ("#test", "Commands: help\r\nhttps://example.com/"), vec! [
+			"Commands: help",
+			"https://example.com/",
+		]);
+		
+		let words = "word ".repeat (300);
+		for msg in split_message ("#test", &words) {
+			assert! (msg.len () <= limit);
+			assert! (! msg.starts_with (' '));
+		}
+		
+		for input in [
+			"a".repeat (1_000),
+			"é".repeat (1_000),
+			"a🏓".repeat (300),
+			format! ("a{}", "日本".repeat (300)),
+		] {
+			let msgs = split_message ("#test", &input);
+			assert! (msgs.len () > 1);
+			for msg in &msgs {
+				assert! (msg.len () <= limit);
+			}
+			assert_eq! (msgs.concat (), input);
+		}
+	}
+	
+	#[test]
+	fn coalescing () {
+		assert_eq! (coalesce (&["Baton timed out.".to_string ()], 0), "Baton timed out.");
+		assert_eq! (coalesce (&[
+			"The baton was taken by alice".to_string (),
+			"A commit was made by alice".to_string (),
+		], 0), "2 updates: The baton was taken by alice | A commit was made by alice");
+		assert_eq! (coalesce (&["Baton timed out.".to_string ()], 9), "10 updates: Baton timed out. | and 9 more");
+	}
+	
+	#[test]
+	fn queue_limit () {
+		let mut queue = VecDeque::new ();
+		for i in 0..MAX_QUEUED_LINES + 5 {
+			enqueue (&mut queue, "#test", &i.to_string ());
+		}
+		
+		assert_eq! (queue.len (), MAX_QUEUED_LINES);
+		assert_eq! (queue.front ().unwrap ().1, "5");
+		assert_eq! (queue.back ().unwrap ().1, (MAX_QUEUED_LINES + 4).to_string ());
+	}
+}
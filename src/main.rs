@@ -1,6 +1,7 @@
 use std::{
-	convert::TryInto,
+	convert::{Infallible, TryInto},
 	fmt::Debug,
+	net::SocketAddr,
 	path::{Path, PathBuf},
 	sync::Arc,
 	time::{Duration, Instant},
@@ -9,10 +10,8 @@ use std::{
 use anyhow::{
 	Context,
 	anyhow,
-	bail,
 };
 use git2::Repository;
-use handlebars::Handlebars;
 use hyper::{
 	Body,
 	Method,
@@ -22,111 +21,229 @@ use hyper::{
 	service::{make_service_fn, service_fn},
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{
+	io::AsyncReadExt,
+	sync::Mutex,
+};
+
+mod archive;
+mod assets;
+mod bundle;
+mod compression;
+mod config;
+mod content_type;
+mod csrf;
+mod error;
+mod fetch;
+mod git_repo;
+mod highlight;
+mod history;
+mod http_cache;
+mod irc_outbox;
+mod markdown;
+mod patch;
+mod proxy;
+mod quarantine;
+mod range;
+mod rate_limit;
+mod safe_path;
+mod templates;
+mod tls;
+mod token_bucket;
+mod tree;
+
+use config::Config;
+use csrf::Csrf;
+use error::Error;
+use history::CommitData;
+use irc_outbox::IrcOutbox;
+use proxy::{Client, Proxy};
+use quarantine::Quarantine;
+use range::Source;
+use rate_limit::RateLimits;
+use safe_path::UnsafePath;
+use templates::Templates;
 
 type Request = hyper::Request <hyper::Body>;
 type ResponseB = hyper::Response <hyper::Body>;
-type ResultResponse = anyhow::Result <ResponseB>;
+type ResultResponse = Result <ResponseB, Error>;
+
+const REPO_PATH: &str = "game/git/repo.git";
+/// Where the repo was, with a working tree, before it went bare
+const LEGACY_REPO_PATH: &str = "game/git/repo";
+const IRC_CONFIG_PATH: &str = "game/irc.toml";
+const CONFIG_PATH: &str = "game/codepong.toml";
 
-const REPO_PATH: &'static str = "game/git/repo";
-const IRC_CONFIG_PATH: &'static str = "game/irc.toml";
+/// Commits per page of `/log`
+const LOG_PAGE_SIZE: usize = 50;
+
+/// Headers for anything served out of the game repo. The CSP sandbox
+/// gives game code an opaque origin, so it can't read codepong's cookies
+/// or drive its forms, even when someone opens a `tree/` URL directly.
+const GAME_CSP: &str = "sandbox allow-scripts allow-pointer-lock; \
+default-src 'self' data: blob:; \
+script-src 'self' 'unsafe-inline' 'unsafe-eval' 'wasm-unsafe-eval' blob:; \
+style-src 'self' 'unsafe-inline'; \
+form-action 'none'; \
+base-uri 'none'; \
+frame-ancestors 'self'";
+
+/// Headers for codepong's own pages, which must never be framed by games
+/// or anyone else
+const PAGE_CSP: &str = "frame-ancestors 'none'; form-action 'self'; base-uri 'none'";
 
 #[tokio::main]
 async fn main () -> anyhow::Result <()> {
-	use std::{
-		convert::Infallible,
-		sync::Arc,
-	};
-	
 	tracing_subscriber::fmt::init ();
 	
-	let baton = Baton::load ().await?;
-	let first_timeout = baton.get ().map (|hold| hold.expiration);
-	let baton = Arc::new (Mutex::new (baton));
+	let config = Config::load (Path::new (CONFIG_PATH))?;
+	git_repo::prepare (Path::new (REPO_PATH), Path::new (LEGACY_REPO_PATH), &config.branches)?;
+	
+	// Fail now rather than on the first request if a template is broken
+	let templates = Arc::new (Templates::load ()?);
+	if std::env::var_os ("CODEPONG_DEV").is_some () {
+		tracing::info! ("Dev mode, watching templates for changes");
+		Arc::clone (&templates).watch ();
+	}
 	
 	let irc_config = irc::client::prelude::Config::load (IRC_CONFIG_PATH)?;
 	let irc_client = irc::client::prelude::Client::from_config (irc_config.clone ()).await?;
 	irc_client.identify ()?;
-	let irc_sender = irc_client.sender ();
+	let irc_outbox = IrcOutbox::spawn (irc_client.sender (), irc_config.channels.clone ());
+	
+	let mut branches = vec! [];
+	for (i, name) in config.branches.all ().enumerate () {
+		let side = i > 0;
+		let baton_path = match side {
+			false => PathBuf::from (BATON_FILE),
+			true => PathBuf::from (format! ("game/baton-{}.json", name)),
+		};
+		
+		let baton = Baton::load (baton_path).await?;
+		let first_timeout = baton.get ().map (|hold| {
+			let epoch_now = chrono::Utc::now ().timestamp ();
+			let remaining = hold.expiration - epoch_now;
+			Instant::now () + Duration::from_secs (remaining.try_into ().unwrap ())
+		});
+		
+		let timeout_msg = match side {
+			false => "Baton timed out.".to_string (),
+			true => format! ("Baton on {} timed out.", name),
+		};
+		
+		branches.push (Branch {
+			name: name.to_string (),
+			side,
+			baton: Mutex::new (baton),
+			timeout_tx: watch_baton_timeout (irc_outbox.clone (), timeout_msg, first_timeout),
+		});
+	}
+	let branches = Arc::new (branches);
+	
+	// The old hard-coded link is still the best guess without a config
+	let help_url = config.http.public_url.as_ref ()
+	.map (|u| u.as_str ().to_string ())
+	.unwrap_or_else (|| "https://six-five-six-four.com/codepong/".to_string ());
 	
-	let baton_2 = Arc::clone (&baton);
+	let branches_2 = Arc::clone (&branches);
+	let irc_outbox_2 = irc_outbox.clone ();
 	tokio::spawn (async move {
 		let mut bot = IrcBot {
 			client: irc_client,
-			baton: baton_2,
+			help_url,
+			outbox: irc_outbox_2,
+			branches: branches_2,
 		};
 		bot.run ().await
 	});
 	
-	let (timeout_tx, mut timeout_rx) = tokio::sync::watch::channel (first_timeout
-	.map (|first_timeout| {
-		let epoch_now = chrono::Utc::now ().timestamp ();
-		let remaining = first_timeout - epoch_now;
-		Instant::now () + Duration::from_secs (remaining.try_into ().unwrap ())
-	}));
-	
 	let code_pong_server = Arc::new (CodePongServer {
-		handlebars: Default::default (),
-		irc_sender,
-		irc_config,
-		baton,
-		timeout_tx,
+		templates,
+		proxy: Proxy::new (&config.http),
+		csrf: Csrf::new ()?,
+		rate_limits: RateLimits::new (&config.rate_limits),
+		fetch_config: config.fetch,
+		irc_outbox,
+		branches,
 	});
 	
-	let server_2 = Arc::clone (&code_pong_server);
 	tokio::spawn (async move {
-		let x = *timeout_rx.borrow ();
-		if let Some (x) = x {
-			tokio::time::sleep_until (x.into ()).await;
-			if *timeout_rx.borrow () == Some (x) {
-				server_2.send_irc_notification ("Baton timed out.").ok ();
-			}
-		}
-		
-		while timeout_rx.changed ().await.is_ok () {
-			let x = *timeout_rx.borrow ();
-			if let Some (x) = x {
-				tokio::time::sleep_until (x.into ()).await;
-				if *timeout_rx.borrow () == Some (x) {
-					server_2.send_irc_notification ("Baton timed out.").ok ();
-				}
+		loop {
+			match tokio::task::spawn_blocking (|| quarantine::cleanup (Path::new (REPO_PATH), quarantine::MAX_AGE)).await {
+				Ok (Ok (())) => (),
+				Ok (Err (e)) => tracing::error! ("Can't clean up quarantines: {:?}", e),
+				Err (e) => tracing::error! ("Can't clean up quarantines: {:?}", e),
 			}
+			tokio::time::sleep (quarantine::MAX_AGE / 4).await;
 		}
 	});
 	
-	let make_svc = make_service_fn (|_conn| {
-		let code_pong_server = Arc::clone (&code_pong_server);
-		
-		async {
-			let f = service_fn (move |req| {
+	match &config.tls {
+		None => {
+			let make_svc = make_service_fn (|conn: &hyper::server::conn::AddrStream| {
 				let code_pong_server = Arc::clone (&code_pong_server);
-				
+				let remote = conn.remote_addr ();
 				async move {
-					let r = code_pong_server.handle_all (req).await;
-					let r = match r {
-						Ok (r) => r,
-						Err (e) => {
-							tracing::error! ("{:?}", e);
-							Response::builder ()
-							.status (StatusCode::INTERNAL_SERVER_ERROR)
-							.body (Body::from (format! ("{:?}", e).as_bytes ().to_vec ())).unwrap ()
-						}
-					};
-					
-					Ok::<_, Infallible> (r)
+					Ok::<_, Infallible> (service_fn (move |req| serve (Arc::clone (&code_pong_server), remote, false, req)))
 				}
 			});
 			
-			Ok::<_, Infallible> (f)
-		}
-	});
+			tracing::info! ("Listening on http://{}", config.http.listen);
+			Server::bind (&config.http.listen).serve (make_svc).await?;
+		},
+		Some (tls_config) => {
+			let certs = Arc::new (tls::Certs::load (tls_config)?);
+			Arc::clone (&certs).watch ();
+			
+			let make_svc = make_service_fn (|conn: &tokio_rustls::server::TlsStream <tokio::net::TcpStream>| {
+				let code_pong_server = Arc::clone (&code_pong_server);
+				let remote = conn.get_ref ().0.peer_addr ()
+				.unwrap_or_else (|_| SocketAddr::from (([0, 0, 0, 0], 0)));
+				async move {
+					Ok::<_, Infallible> (service_fn (move |req| serve (Arc::clone (&code_pong_server), remote, true, req)))
+				}
+			});
+			
+			let https_port = tls_config.listen.port ();
+			let make_redirect_svc = make_service_fn (|_conn| async move {
+				Ok::<_, Infallible> (service_fn (move |req| async move {
+					Ok::<_, Infallible> (tls::redirect (&req, https_port))
+				}))
+			});
+			
+			let listener = tokio::net::TcpListener::bind (tls_config.listen).await
+			.with_context (|| format! ("Can't listen on {}", tls_config.listen))?;
+			
+			tracing::info! ("Listening on https://{}, redirecting from http://{}", tls_config.listen, config.http.listen);
+			tokio::try_join! (
+				Server::builder (tls::incoming (listener, certs)).serve (make_svc),
+				Server::bind (&config.http.listen).serve (make_redirect_svc),
+			)?;
+		},
+	}
+	
+	Ok (())
+}
+
+async fn serve (
+	code_pong_server: Arc <CodePongServer>,
+	remote: SocketAddr,
+	tls: bool,
+	req: Request,
+) -> Result <ResponseB, Infallible>
+{
+	let client = code_pong_server.proxy.client (req.headers (), remote, tls);
+	let method = req.method ().clone ();
+	let path = req.uri ().path ().to_string ();
 	
-	let server = Server::bind (&std::net::SocketAddr::from (([0, 0, 0, 0], 4000)))
-	.serve (make_svc);
+	let r = match code_pong_server.handle_all (req, client).await {
+		Ok (r) => r,
+		Err (e) => code_pong_server.error_response (e).await,
+	};
 	
-	server.await?;
+	tracing::info! ("{} {} {} {}", client.ip, method, path, r.status ().as_u16 ());
 	
-	Ok (())
+	Ok (r)
 }
 
 #[derive (Deserialize, Serialize)]
@@ -138,9 +255,12 @@ struct BatonHold {
 #[derive (Default, Deserialize, Serialize)]
 struct Baton {
 	hold: Option <BatonHold>,
+	
+	#[serde (skip)]
+	path: PathBuf,
 }
 
-const BATON_FILE: &'static str = "game/baton.json";
+const BATON_FILE: &str = "game/baton.json";
 
 impl Baton {
 	fn status (&self) -> String {
@@ -167,23 +287,29 @@ impl Baton {
 		Some (hold)
 	}
 	
-	async fn load () -> anyhow::Result <Self> {
-		let s = match tokio::fs::read_to_string (BATON_FILE).await {
-			Err (_) => return Ok (Self::default ()),
+	async fn load (path: PathBuf) -> anyhow::Result <Self> {
+		let s = match tokio::fs::read_to_string (&path).await {
+			Err (_) => return Ok (Self {
+				path,
+				..Self::default ()
+			}),
 			Ok (x) => x,
 		};
 		
 		let b: Baton = serde_json::from_str (&s)?;
 		
-		Ok (b)
+		Ok (Self {
+			path,
+			..b
+		})
 	}
 	
 	async fn save (&self) -> anyhow::Result <()> {
 		let s = serde_json::to_string (&self)?;
 		
-		let temp_path = format! ("{}.temp", BATON_FILE);
-		tokio::fs::write (Path::new (&temp_path), s).await?;
-		tokio::fs::rename (temp_path, BATON_FILE).await?;
+		let temp_path = self.path.with_extension ("json.temp");
+		tokio::fs::write (&temp_path, s).await?;
+		tokio::fs::rename (temp_path, &self.path).await?;
 		
 		Ok (())
 	}
@@ -220,28 +346,108 @@ impl Baton {
 	}
 }
 
-struct CodePongServer <'a> {
-	handlebars: Handlebars <'a>,
-	irc_sender: irc::client::Sender,
-	irc_config: irc::client::prelude::Config,
-	baton: Arc <Mutex <Baton>>,
+/// A branch that players take turns on, with its own baton
+struct Branch {
+	name: String,
+	
+	/// False for the main branch
+	side: bool,
+	
+	baton: Mutex <Baton>,
 	timeout_tx: tokio::sync::watch::Sender <Option <Instant>>,
 }
 
+impl Branch {
+	/// Tells IRC which branch a message is about, unless it's `main`
+	fn on (&self) -> String {
+		match self.side {
+			false => String::new (),
+			true => format! (" on {}", self.name),
+		}
+	}
+}
+
+/// Sends `msg` to IRC when a baton runs out. Taking or passing on the
+/// baton sends the new deadline, or `None`, down the channel.
+fn watch_baton_timeout (irc_outbox: IrcOutbox, msg: String, first_timeout: Option <Instant>) -> tokio::sync::watch::Sender <Option <Instant>>
+{
+	let (timeout_tx, mut timeout_rx) = tokio::sync::watch::channel (first_timeout);
+	
+	tokio::spawn (async move {
+		let x = *timeout_rx.borrow ();
+		if let Some (x) = x {
+			tokio::time::sleep_until (x.into ()).await;
+			if *timeout_rx.borrow () == Some (x) {
+				irc_outbox.notify (&msg).ok ();
+			}
+		}
+		
+		while timeout_rx.changed ().await.is_ok () {
+			let x = *timeout_rx.borrow ();
+			if let Some (x) = x {
+				tokio::time::sleep_until (x.into ()).await;
+				if *timeout_rx.borrow () == Some (x) {
+					irc_outbox.notify (&msg).ok ();
+				}
+			}
+		}
+	});
+	
+	timeout_tx
+}
+
+struct CodePongServer {
+	templates: Arc <Templates>,
+	proxy: Proxy,
+	csrf: Csrf,
+	rate_limits: RateLimits,
+	fetch_config: config::Fetch,
+	irc_outbox: IrcOutbox,
+	
+	/// The main branch first
+	branches: Arc <Vec <Branch>>,
+}
+
 #[derive (Serialize)]
 struct CommitPage {
+	root: String,
 	commit_id: String,
-	entries: Vec <EntryData>,
+	id_short: String,
+	
+	/// The listed directory, percent-encoded and with a trailing slash
+	/// unless it's the root
+	dir: String,
+	crumbs: Vec <Crumb>,
+	entries: Vec <ListedEntry>,
+	readme: Option <Readme>,
+}
+
+#[derive (Serialize)]
+struct Readme {
+	name: String,
+	html: String,
 }
 
+/// A listing entry with its percent-encoded name, for links
 #[derive (Serialize)]
-struct EntryData {
-	name: Option <String>,
+struct ListedEntry {
+	#[serde (flatten)]
+	entry: tree::Entry,
+	href: String,
+}
+
+/// A link to one of the directories above a file or directory
+#[derive (Serialize)]
+struct Crumb {
+	name: String,
+	url: String,
 }
 
 struct IrcBot {
 	client: irc::client::prelude::Client,
-	baton: Arc <Mutex <Baton>>,
+	help_url: String,
+	outbox: IrcOutbox,
+	branches: Arc <Vec <Branch>>,
 }
 
 impl IrcBot {
@@ -257,9 +463,8 @@ impl IrcBot {
 				_ => continue,
 			};
 			
-			match self.handle_privmsg (&channel, &message).await {
-				Err (e) => tracing::error! ("{:?}", e),
-				Ok (_) => (),
+			if let Err (e) = self.handle_privmsg (&channel, &message).await {
+				tracing::error! ("{:?}", e);
 			}
 		}
 		
@@ -271,26 +476,30 @@ impl IrcBot {
 	{
 		use BotCommand::*;
 		
-		let cmd = match parse_irc_privmsg (self.client.current_nickname (), &message) {
+		let cmd = match parse_irc_privmsg (self.client.current_nickname (), message) {
 			Some (x) => x,
 			None => return Ok (()),
 		};
 		
 		let reply = match cmd {
-			Help => "Commands: help, status, head\r\nhttps://six-five-six-four.com/codepong/".to_string (),
+			Help => format! ("Commands: help, status, head\r\n{}", self.help_url),
 			GetStatus => self.handle_status ().await?,
-			GetLastCommit => Self::handle_head ()?,
+			GetLastCommit => self.handle_head ()?,
 		};
 		
-		self.client.send_privmsg (&channel, reply).unwrap ();
+		self.outbox.reply (channel, &reply)?;
 		
 		Ok (())
 	}
 	
-	fn handle_head () -> anyhow::Result <String>
+	fn handle_head (&self) -> anyhow::Result <String>
 	{
-		let commits = get_last_commits (1)?;
-		let commit = match commits.get (0) {
+		let log = {
+			let repo = Repository::open (REPO_PATH)?;
+			history::log (&repo, &self.branches [0].name, None, 1, false)
+			.map_err (|e| anyhow::anyhow! ("{}", e))?
+		};
+		let commit = match log.commits.first () {
 			None => return Ok ("No commits yet".to_string ()),
 			Some (x) => x,
 		};
@@ -300,14 +509,38 @@ impl IrcBot {
 	
 	async fn handle_status (&self) -> anyhow::Result <String>
 	{
-		let baton = self.baton.lock ().await;
-		Ok (baton.status ())
+		let mut lines = vec! [];
+		for branch in self.branches.iter () {
+			let status = branch.baton.lock ().await.status ();
+			lines.push (match branch.side {
+				false => status,
+				true => format! ("{}: {}", branch.name, status),
+			});
+		}
+		Ok (lines.join ("\r\n"))
 	}
 }
 
-impl CodePongServer <'_> {
+impl CodePongServer {
 	#[tracing::instrument (level = "debug", skip (self, req))]
-	async fn handle_all (&self, req: Request) -> ResultResponse
+	async fn handle_all (&self, req: Request, client: Client) -> ResultResponse
+	{
+		let is_head = req.method () == Method::HEAD;
+		let req_headers = req.headers ().clone ();
+		
+		let resp = self.route (req, client).await?;
+		let mut resp = compression::compress (&req_headers, is_head, resp);
+		
+		if is_head {
+			// Routes that allow HEAD build the same response as for GET,
+			// with an explicit Content-Length, so we only drop the body
+			*resp.body_mut () = Body::empty ();
+		}
+		
+		Ok (resp)
+	}
+	
+	async fn route (&self, req: Request, client: Client) -> ResultResponse
 	{
 		use std::future::Future;
 		
@@ -316,48 +549,67 @@ impl CodePongServer <'_> {
 		F: Send + Future <Output = ResultResponse>,
 		H: Send + FnOnce (Request) -> F
 		{
-			match req.method () {
-				&Method::GET => f (req).await,
+			match *req.method () {
+				Method::GET => f (req).await,
 				_ => method_not_allowed (),
 			}
 		}
 		
 		fn method_not_allowed () -> ResultResponse { 
-			Ok (handle_error (StatusCode::METHOD_NOT_ALLOWED, "405 Method Not Allowed")?)
+			Err (Error::MethodNotAllowed)
 		}
 		
-		let uri = req.uri ().path ();
+		let uri = self.proxy.strip_base (req.uri ().path ());
 		tracing::debug! ("URI: {}", uri);
 		
+		if req.method () == Method::POST {
+			self.rate_limits.post_per_ip.check (&client.ip.to_string ())?;
+		}
+		
 		if let Some (tail) = uri.strip_prefix ("/static/") {
-			match req.method () {
-				&Method::GET => self.handle_static (tail).await,
+			match *req.method () {
+				Method::GET | Method::HEAD => self.handle_static (req.headers (), tail).await,
 				_ => method_not_allowed (),
 			}
 		}
 		else if uri == "/" {
 			Ok (Response::builder ()
 			.status (StatusCode::TEMPORARY_REDIRECT)
-			.header ("location", "home")
+			.header ("location", format! ("{}home", self.proxy.base_path ()))
 			.body (Body::from ("Redirecting to home..."))?)
 		}
 		else if uri == "/home" {
-			get_only (req, |_| self.handle_index ()).await
+			get_only (req, |req| async move {
+				self.handle_index (req.headers (), client).await
+			}).await
+		}
+		else if uri == "/log" {
+			get_only (req, |req| async move {
+				self.handle_log (req.uri ().query ()).await
+			}).await
 		}
 		else if uri == "/next" {
-			match req.method () {
-				&Method::GET => self.handle_next_get ().await,
-				&Method::POST => self.handle_next_post (req).await,
+			match *req.method () {
+				Method::GET => self.handle_next_get (req.headers (), req.uri ().query (), client).await,
+				Method::POST => self.handle_next_post (req, client).await,
 				_ => method_not_allowed (),
 			}
 		}
 		else if uri == "/commit" {
-			match req.method () {
-				&Method::GET => self.handle_commit_get ().await,
-				&Method::POST => self.handle_commit_post (req).await,
+			match *req.method () {
+				Method::GET => self.handle_commit_get (req.headers (), req.uri ().query (), client).await,
+				Method::POST => self.handle_commit_post (req, client).await,
 				_ => method_not_allowed (),
 			}
 		}
+		else if uri == "/metrics" {
+			get_only (req, |_| async move {
+				Ok (Response::builder ()
+				.header ("content-type", "text/plain; version=0.0.4; charset=utf-8")
+				.header ("cache-control", "no-store")
+				.body (Body::from (self.rate_limits.metrics ()))?)
+			}).await
+		}
 		else if uri == "/debug" {
 			// self.send_irc_notification ("Someone clicked something!")?;
 			
@@ -365,242 +617,435 @@ impl CodePongServer <'_> {
 			.body (Body::from ("Ok"))?)
 		}
 		else if let Some (tail) = uri.strip_prefix ("/git/") {
-			match req.method () {
-				&Method::GET => self.handle_git (tail).await,
+			self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+			
+			match *req.method () {
+				Method::GET | Method::HEAD => self.handle_git (req.headers (), tail).await,
 				_ => method_not_allowed (),
 			}
 		}
 		else if let Some (tail) = uri.strip_prefix ("/tree/") {
-			match req.method () {
-				&Method::GET => self.handle_tree (tail).await,
+			match *req.method () {
+				Method::GET | Method::HEAD => self.handle_tree (req.headers (), tail, client).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/archive/") {
+			// As expensive as a clone
+			self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+			
+			match *req.method () {
+				Method::GET => self.handle_archive (tail).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/api/tree/") {
+			// Same work as a listing in /tree/
+			self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+			
+			match *req.method () {
+				Method::GET => self.handle_api_tree (tail).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/play/") {
+			match *req.method () {
+				Method::GET => self.handle_play (tail).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/source/") {
+			match *req.method () {
+				Method::GET => self.handle_source (tail, req.uri ().query ()).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/history/") {
+			// Walks the whole history, like a clone
+			self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+			
+			match *req.method () {
+				Method::GET => self.handle_file_history (tail, req.uri ().query ()).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/blame/") {
+			// Goes back through every commit that touched the file
+			self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+			
+			match *req.method () {
+				Method::GET => self.handle_blame (tail).await,
+				_ => method_not_allowed (),
+			}
+		}
+		else if let Some (tail) = uri.strip_prefix ("/diff/") {
+			match *req.method () {
+				Method::GET | Method::HEAD => self.handle_diff (req.headers (), tail).await,
 				_ => method_not_allowed (),
 			}
 		}
 		else {
-			Ok (handle_error (StatusCode::NOT_FOUND, "404 Not Found")?)
+			Err (Error::not_found ("There's no page here."))
 		}
 	}
 	
 	fn send_irc_notification (&self, msg: &str) -> anyhow::Result <()>
 	{
-		for channel in &self.irc_config.channels {
-			self.irc_sender.send_privmsg (channel, msg)?;
+		self.irc_outbox.notify (msg)
+	}
+	
+	/// Looks up a `branch` parameter. Empty means the main branch.
+	fn branch (&self, name: &str) -> Result <&Branch, Error>
+	{
+		if name.is_empty () {
+			return Ok (&self.branches [0]);
 		}
 		
-		Ok (())
+		self.branches.iter ()
+		.find (|b| b.name == name)
+		.ok_or_else (|| Error::not_found (format! ("There's no branch called `{}` here", name)))
+	}
+	
+	/// The branch from a `?branch=` query string
+	fn branch_from_query (&self, query: Option <&str>) -> Result <&Branch, Error>
+	{
+		#[derive (Deserialize)]
+		struct Query {
+			#[serde (default)]
+			branch: String,
+		}
+		
+		let query: Query = serde_urlencoded::from_str (query.unwrap_or (""))
+		.map_err (|_| Error::bad_request ("Bad query string"))?;
+		self.branch (&query.branch)
 	}
 	
-	async fn handle_index (&self) -> anyhow::Result <ResponseB>
+	async fn handle_index (&self, headers: &hyper::HeaderMap, client: Client) -> ResultResponse
 	{
 		#[derive (Serialize)]
-		struct Page <'a> {
-			commits: Vec <CommitDisplay <'a>>,
+		struct Page {
+			commits: Vec <CommitData>,
 			commit_count: usize,
+			more: bool,
 			kb_free: u64,
 			baton_status: String,
+			side_branches: Vec <SideBranch>,
+			clone_url: Option <String>,
 		}
 		
 		#[derive (Serialize)]
-		struct CommitDisplay <'a> {
-			id: &'a str,
-			id_short: &'a str,
-			author: Option <&'a str>,
-			time: &'a str,
-			message: Option <&'a str>,
+		struct SideBranch {
+			name: String,
+			baton_status: String,
+			commits: Vec <CommitData>,
+			more: bool,
 		}
 		
-		let commits = get_last_commits (20)?;
+		let main = &self.branches [0];
+		
+		// git2 objects can't be held across an await
+		let (main_log, side_logs) = {
+			let repo = Repository::open (REPO_PATH)?;
+			let main_log = history::log (&repo, &main.name, None, 20, false)?;
+			let side_logs = self.branches.iter ()
+			.filter (|b| b.side)
+			.map (|b| history::log (&repo, &b.name, None, 10, false))
+			.collect::<Result <Vec <_>, _>> ()?;
+			(main_log, side_logs)
+		};
 		
 		// I hate UOM and I hate heim too
 		let kb_free = heim::disk::usage (REPO_PATH).await?
 		.free ().get::<uom::si::information::kibibyte> ();
 		
-		let baton_status = {
-			let baton = self.baton.lock ().await;
-			baton.status ()
-		};
+		let baton_status = main.baton.lock ().await.status ();
+		
+		let mut side_branches = vec! [];
+		for (branch, log) in self.branches.iter ().filter (|b| b.side).zip (side_logs) {
+			side_branches.push (SideBranch {
+				name: branch.name.clone (),
+				baton_status: branch.baton.lock ().await.status (),
+				more: log.next.is_some (),
+				commits: log.commits,
+			});
+		}
 		
 		let page = Page {
-			commits: commits.iter ()
-			.map (|c| CommitDisplay {
-				id: &c.id,
-				id_short: &c.id [0..8],
-				author: c.author.as_deref (),
-				time: &c.time,
-				message: c.message.as_deref (),
-			})
-			.collect (),
-			commit_count: commits.len (),
+			commit_count: main_log.commits.len (),
+			more: main_log.next.is_some (),
+			commits: main_log.commits,
 			kb_free,
 			baton_status,
+			side_branches,
+			clone_url: self.proxy.public_url (headers, client).map (|u| format! ("{}git/", u)),
+		};
+		
+		self.template_response ("index", &page).await
+	}
+	
+	async fn handle_log (&self, query: Option <&str>) -> ResultResponse
+	{
+		#[derive (Deserialize)]
+		struct Query {
+			#[serde (default)]
+			branch: String,
+			after: Option <String>,
+			#[serde (default)]
+			first_parent: bool,
+		}
+		
+		#[derive (Serialize)]
+		struct Page {
+			side_branch: Option <String>,
+			commits: Vec <CommitData>,
+			first_parent: bool,
+			toggle_url: String,
+			next_url: Option <String>,
+		}
+		
+		let query: Query = serde_urlencoded::from_str (query.unwrap_or (""))
+		.map_err (|_| Error::bad_request ("Bad query string"))?;
+		let branch = self.branch (&query.branch)?;
+		
+		let after = parse_after (query.after.as_deref ())?;
+		
+		let log = {
+			let repo = Repository::open (REPO_PATH)?;
+			history::log (&repo, &branch.name, after, LOG_PAGE_SIZE, query.first_parent)?
+		};
+		
+		let side_branch = Some (branch.name.clone ()).filter (|_| branch.side);
+		
+		let page = Page {
+			toggle_url: log_url (side_branch.as_deref (), None, ! query.first_parent),
+			next_url: log.next.map (|next| log_url (side_branch.as_deref (), Some (next), query.first_parent)),
+			side_branch,
+			commits: log.commits,
+			first_parent: query.first_parent,
 		};
 		
-		self.template_response ("handlebars/index.hbs", &page).await
+		self.template_response ("log", &page).await
 	}
 	
-	async fn handle_next_post (&self, req: Request) -> ResultResponse 
+	async fn handle_next_post (&self, req: Request, client: Client) -> ResultResponse 
 	{
 		#[derive (Deserialize)]
 		struct PostData {
 			username: String,
+			#[serde (default)]
+			csrf_token: String,
+			#[serde (default)]
+			branch: String,
 		}
 		
-		let (_parts, body) = req.into_parts ();
+		let (parts, body) = req.into_parts ();
 		let form_data = read_body_limited (body, 1_024).await?;
-		let data: PostData = serde_urlencoded::from_bytes (&form_data)?;
+		let data: PostData = serde_urlencoded::from_bytes (&form_data)
+		.map_err (form_error)?;
+		self.check_csrf (&parts.headers, client, &data.csrf_token)?;
+		self.rate_limits.post_per_user.check (&data.username.trim ().to_lowercase ())?;
+		let branch = self.branch (&data.branch)?;
 		
 		let hold_seconds: u32 = 3_600;
 		
 		{
-			let mut baton = self.baton.lock ().await;
+			let mut baton = branch.baton.lock ().await;
 			if ! baton.next (data.username.clone (), hold_seconds.into ()).await? {
-				bail! ("Someone (maybe you) already has the baton.");
+				return Err (Error::bad_request ("Someone (maybe you) already has the baton."));
 			}
 		}
 		
-		self.send_irc_notification (&format! ("The baton was taken by {}", data.username))?;
-		self.timeout_tx.send (Some (Instant::now () + Duration::from_secs (hold_seconds.into ())))?;
+		self.send_irc_notification (&format! ("The baton{} was taken by {}", branch.on (), data.username))?;
+		branch.timeout_tx.send (Some (Instant::now () + Duration::from_secs (hold_seconds.into ())))?;
 		
 		Ok (Response::builder ()
 		.status (StatusCode::SEE_OTHER)
-		.header ("location", "home")
+		.header ("location", format! ("{}home", self.proxy.base_path ()))
 		.body (Body::from ("Took the baton!"))?)
 	}
 	
-	async fn handle_commit_post (&self, req: Request) -> ResultResponse
+	async fn handle_commit_post (&self, req: Request, client: Client) -> ResultResponse
 	{
-		#[derive (Deserialize)]
-		struct PostData {
-			username: String,
-			url: String,
+		let (parts, body) = req.into_parts ();
+		let (mut data, uploads) = read_commit_form (&parts.headers, body, self.fetch_config.max_bytes).await?;
+		self.check_csrf (&parts.headers, client, &data.csrf_token)?;
+		self.rate_limits.post_per_user.check (&data.username.trim ().to_lowercase ())?;
+		let branch = self.branch (&data.branch)?;
+		
+		// Fail fast, but don't hold the lock during the upload or fetch.
+		// It's checked again below in case the baton changed hands in the
+		// meantime.
+		if ! branch.baton.lock ().await.can_commit (&data.username) {
+			return Err (Error::bad_request ("You can't commit now. Someone else has the baton."));
 		}
 		
-		let (_parts, body) = req.into_parts ();
-		let form_data = read_body_limited (body, 1_024).await?;
-		let data: PostData = serde_urlencoded::from_bytes (&form_data)?;
-		
-		if ! data.url.starts_with ("https://") && ! data.url.starts_with ("ssh://")
-		{
-			bail! ("Git remote URL must use HTTPS or SSH protocol");
+		if let Some (uploads) = uploads {
+			read_uploads (&mut data, uploads).await?;
 		}
 		
-		let repo = Repository::open (REPO_PATH)?;
+		let remote = match data.bundle.is_empty () && data.patch.trim ().is_empty () {
+			true => Some (fetch::parse_url (&data.url, &self.fetch_config)?),
+			false => None,
+		};
+		
+		// Everything from the player lands in a quarantine first, and only
+		// reaches the real repo if the commit is accepted
+		let quarantine = {
+			let branch_name = branch.name.clone ();
+			tokio::task::spawn_blocking (move || Quarantine::new (Path::new (REPO_PATH), &branch_name)).await??
+		};
+		let quarantine_path = quarantine.path ().to_path_buf ();
+		
+		let (fetched, source) = match remote {
+			Some (remote) => (fetch::fetch (&quarantine_path, remote, &branch.name, &self.fetch_config).await?, data.url.as_str ()),
+			None if ! data.bundle.is_empty () => {
+				let bundle = data.bundle;
+				let branch_name = branch.name.clone ();
+				let oid = tokio::task::spawn_blocking (move || bundle::unbundle (&quarantine_path, &bundle, &branch_name)).await??;
+				(oid, "the uploaded bundle")
+			},
+			None => {
+				let series = data.patch;
+				let oid = tokio::task::spawn_blocking (move || patch::apply (&quarantine_path, &series)).await??;
+				(oid, "the patches")
+			},
+		};
 		
 		{
-			let mut baton = self.baton.lock ().await;
+			let mut baton = branch.baton.lock ().await;
 			if ! baton.can_commit (&data.username) {
-				bail! ("You can't commit now. Someone else has the baton.");
+				return Err (Error::bad_request ("You can't commit now. Someone else has the baton."));
 			}
 			
-			{
-				let mut remote = repo.remote_anonymous (&data.url)?;
-				remote.fetch (&["main"], None, None)?;
-				
-				let fetch_head_obj = repo.revparse_single ("FETCH_HEAD")?;
-				let fetch_head = repo.annotated_commit_from_fetchhead ("main", &data.url, &fetch_head_obj.id ())?;
-				let (analysis, _) = repo.merge_analysis (&[&fetch_head])?;
-				
-				if analysis.is_up_to_date () {
-					bail! ("Already up-to-date with `main` on that remote");
-				}
-				if ! analysis.is_fast_forward () {
-					bail! ("Cannot fast-forward merge to Git remote's `main` branch");
-				}
-				
-				repo.reset (&fetch_head_obj, git2::ResetType::Hard, None)?;
-			}
+			// Still under the baton, so nobody can commit in between
+			let branch_name = branch.name.clone ();
+			let source = source.to_string ();
+			let log_message = format! ("codepong: commit by {}", data.username);
+			tokio::task::spawn_blocking (move || fast_forward (quarantine, fetched, &branch_name, &source, &log_message)).await??;
 			
 			baton.commit (&data.username).await?;
 		}
 		
-		self.timeout_tx.send (None)?;
-		self.send_irc_notification (&format! ("A commit was made by {}", data.username))?;
+		branch.timeout_tx.send (None)?;
+		self.send_irc_notification (&format! ("A commit was made{} by {}", branch.on (), data.username))?;
 		
-		let msg = format! ("Fast-forwarded to {}!", data.url);
+		let msg = format! ("Fast-forwarded to {}!", source);
 		
 		Ok (Response::builder ()
 		.status (StatusCode::SEE_OTHER)
-		.header ("location", "home")
+		.header ("location", format! ("{}home", self.proxy.base_path ()))
 		.body (Body::from (msg))?)
 	}
 	
-	async fn handle_next_get (&self) -> ResultResponse
+	async fn handle_next_get (&self, headers: &hyper::HeaderMap, query: Option <&str>, client: Client) -> ResultResponse
 	{
 		#[derive (Serialize)]
 		struct Page {
-			
+			holding_username: Option <String>,
+			csrf_token: String,
+			side_branch: Option <String>,
 		}
 		
+		let branch = self.branch_from_query (query)?;
+		
+		let holding_username = {
+			let baton = branch.baton.lock ().await;
+			baton.get ().map (|hold| hold.username.clone ())
+		};
+		
+		let csrf = self.csrf.issue (headers, self.proxy.base_path (), client.https)?;
+		
 		let page = Page {
-			
+			holding_username,
+			csrf_token: csrf.token,
+			side_branch: Some (branch.name.clone ()).filter (|_| branch.side),
 		};
 		
-		self.template_response ("handlebars/next.hbs", &page).await
+		let resp = self.template_response ("next", &page).await?;
+		Ok (with_session (resp, csrf.set_cookie))
 	}
 	
-	async fn handle_commit_get (&self) -> ResultResponse
+	async fn handle_commit_get (&self, headers: &hyper::HeaderMap, query: Option <&str>, client: Client) -> ResultResponse
 	{
 		#[derive (Serialize)]
 		struct Page {
 			holding_username: Option <String>,
+			csrf_token: String,
+			side_branch: Option <String>,
 		}
 		
+		let branch = self.branch_from_query (query)?;
+		
 		let holding_username = {
-			let baton = self.baton.lock ().await;
+			let baton = branch.baton.lock ().await;
 			baton.get ().map (|hold| hold.username.clone ())
 		};
 		
+		let csrf = self.csrf.issue (headers, self.proxy.base_path (), client.https)?;
+		
 		let page = Page {
 			holding_username,
+			csrf_token: csrf.token,
+			side_branch: Some (branch.name.clone ()).filter (|_| branch.side),
 		};
 		
-		self.template_response ("handlebars/commit.hbs", &page).await
+		let resp = self.template_response ("commit", &page).await?;
+		Ok (with_session (resp, csrf.set_cookie))
+	}
+	
+	/// Rejects POSTs that didn't come from one of our own forms
+	fn check_csrf (&self, headers: &hyper::HeaderMap, client: Client, token: &str) -> Result <(), Error>
+	{
+		let origin = self.proxy.origin (headers, client);
+		self.csrf.verify (headers, origin.as_deref (), token)
 	}
 	
-	async fn handle_git (&self, tail: &str) -> ResultResponse 
+	async fn handle_git (&self, headers: &hyper::HeaderMap, tail: &str) -> ResultResponse 
 	{
-		if tail == "" {
-			bail! ("Need a path in the Git URL");
+		if tail.is_empty () {
+			return Err (Error::not_found ("Need a path in the Git URL"));
 		}
 		
 		if tail == "info/refs" {
-			return self.handle_git_info_refs ().await;
+			return self.handle_git_info_refs (headers).await;
 		}
 		if tail == "objects/info/packs" {
-			return self.handle_git_objects_info_packs ().await;
+			return self.handle_git_objects_info_packs (headers).await;
 		}
 		
-		let bytes = tokio::fs::read (PathBuf::from (REPO_PATH).join (Path::new (".git")).join (tail)).await;
+		let path = safe_path::resolve (Path::new (REPO_PATH), tail).await?;
 		
-		if let Err (_) = bytes {
-			return Ok (Response::builder ()
-			.status (StatusCode::NOT_FOUND)
-			.body (Body::empty ())?)
-		}
-		let bytes = bytes?;
+		let file = tokio::fs::File::open (path).await
+		.map_err (|_| Error::not_found ("No such file in the Git repo"))?;
 		
-		Ok (Response::builder ()
-		.body (Body::from (bytes))?)
+		let builder = Response::builder ()
+		.header ("content-type", git_content_type (tail));
+		
+		Ok (range::respond (headers, None, builder, Source::File (file)).await?)
 	}
 	
-	async fn handle_git_info_refs (&self) -> ResultResponse {
-		let repo = Repository::open (REPO_PATH)?;
-		
+	async fn handle_git_info_refs (&self, headers: &hyper::HeaderMap) -> ResultResponse {
 		let mut body = String::new ();
 		
-		let mut references: Vec <git2::Reference> = repo.references ()?.collect::<Result <Vec <git2::Reference>, _>> ()?;
-		references.sort_by_key (|r| r.name ().map (|s| s.to_string ()));
-		
-		for reference in &references {
-			body.push_str (&format! ("{}\t{}\n", reference.resolve ()?.target ().unwrap (), reference.name ().unwrap ()));
+		{
+			let repo = Repository::open (REPO_PATH)?;
+			
+			let mut references: Vec <git2::Reference> = repo.references ()?.collect::<Result <Vec <git2::Reference>, _>> ()?;
+			references.sort_by_key (|r| r.name ().map (|s| s.to_string ()));
+			
+			for reference in &references {
+				body.push_str (&format! ("{}\t{}\n", reference.resolve ()?.target ().unwrap (), reference.name ().unwrap ()));
+			}
 		}
 		
-		Ok (Response::builder ()
-		.body (Body::from (body))?)
+		let builder = Response::builder ()
+		.header ("content-type", "text/plain; charset=utf-8");
+		
+		Ok (range::respond (headers, None, builder, Source::Memory (body.into_bytes ())).await?)
 	}
 	
-	async fn handle_git_objects_info_packs (&self) -> ResultResponse {
-		let mut iter = tokio::fs::read_dir (PathBuf::from (REPO_PATH).join (".git/objects/pack")).await.with_context (|| "Can't open .git/objects/pack")?;
+	async fn handle_git_objects_info_packs (&self, headers: &hyper::HeaderMap) -> ResultResponse {
+		let mut iter = tokio::fs::read_dir (PathBuf::from (REPO_PATH).join ("objects/pack")).await.with_context (|| "Can't open objects/pack")?;
 		
 		let mut body = String::new ();
 		
@@ -615,165 +1060,765 @@ impl CodePongServer <'_> {
 			body.push_str (&format! ("P {}\n", name));
 		}
 		
-		Ok (Response::builder ()
-		.body (Body::from (body))?)
+		let builder = Response::builder ()
+		.header ("content-type", "text/plain; charset=utf-8");
+		
+		Ok (range::respond (headers, None, builder, Source::Memory (body.into_bytes ())).await?)
 	}
 	
-	async fn handle_tree (&self, tail: &str) -> ResultResponse
+	async fn handle_tree (&self, headers: &hyper::HeaderMap, tail: &str, client: Client) -> ResultResponse
 	{
-		let commit_id = (&tail [0..40]).to_string ();
-		let tail = &tail [41..];
+		let root = "../".repeat (1 + tail.matches ('/').count ());
+		let (rev, rel) = match tail.split_once ('/') {
+			Some (x) => x,
+			None => {
+				if ! is_valid_rev (tail) {
+					return Err (bad_rev (tail));
+				}
+				
+				// Relative links in the listing need the trailing slash
+				return Ok (Response::builder ()
+				.status (StatusCode::PERMANENT_REDIRECT)
+				.header ("location", format! ("{}tree/{}/", self.proxy.base_path (), tail))
+				.body (Body::from ("Redirecting..."))?);
+			},
+		};
+		let path = safe_path::relative_path (rel)?;
+		let dirs: Vec <_> = path.iter ().map (|c| c.to_string_lossy ().to_string ()).collect ();
+		let dir: String = dirs.iter ().map (|d| format! ("{}/", safe_path::url_segment (d))).collect ();
 		
-		let entries;
+		let commit_oid;
+		let commit_id;
+		// git2 objects can't be held across an await, so blobs are
+		// sent after this block
+		let mut blob_response = None;
 		
 		{
 			let repo = Repository::open (REPO_PATH)?;
-			let oid = git2::Oid::from_str (&commit_id)
-			.context ("Failed to make commit ID into OID")?;
-			let commit = repo.find_commit (oid)
-			.context ("Failed to find_commit")?;
+			let commit = resolve_commit (&repo, rev)?;
+			commit_oid = commit.id ();
+			commit_id = commit_oid.to_string ();
 			let tree = commit.tree ()
 			.context ("Failed to get commit's tree")?;
 			
-			if tail == "" {
-				entries = tree.iter ()
-				.map (|entry| {
-					let name = match entry.kind () {
-						Some (git2::ObjectType::Tree) => entry.name ().map (|s| format! ("{}/", s)),
-						_ => entry.name ().map (|s| s.to_string ()),
-					};
-					
-					EntryData {
-						name,
-					}
-				})
-				.collect ();
-			}
-			else {
-				let obj = tree.get_path (&Path::new (tail))
-				.context ("Failed to get_path on tree")?
-				.to_object (&repo)?;
+			if ! path.as_os_str ().is_empty () {
+				let entry = tree.get_path (&path)
+				.map_err (|e| match e.code () {
+					git2::ErrorCode::NotFound => Error::not_found (format! ("No file or directory at `{}` in commit {}", path.display (), commit_id)),
+					_ => e.into (),
+				})?;
+				
+				// Only a full commit ID pins the content, branch names and
+				// short IDs can point somewhere else later
+				let cache_control = match rev == commit_id {
+					true => http_cache::IMMUTABLE,
+					false => http_cache::REVALIDATE,
+				};
+				let etag = http_cache::git_etag (entry.id ());
+				
+				if entry.kind () == Some (git2::ObjectType::Blob) && http_cache::etag_matches (headers, &etag) {
+					return Ok (http_cache::not_modified (&etag, cache_control)?);
+				}
+				
+				let obj = entry.to_object (&repo)?;
 				
 				match obj.kind () {
 					Some (git2::ObjectType::Tree) => {
-						let tree = obj.into_tree ().map_err (|_| anyhow! ("Failed into_tree"))?;
-						
-						entries = tree.iter ()
-						.map (|entry| {
-							let name = entry.name ().map (|s| s.to_string ());
-							
-							EntryData {
-								name,
-							}
-						})
-						.collect ();
+						if ! rel.ends_with ('/') {
+							return Ok (Response::builder ()
+							.status (StatusCode::PERMANENT_REDIRECT)
+							.header ("location", format! ("{}tree/{}/", self.proxy.base_path (), tail))
+							.body (Body::from ("Redirecting..."))?);
+						}
 					},
 					Some (git2::ObjectType::Blob) => {
 						let blob = obj.into_blob ().map_err (|_| anyhow! ("Failed into_blob"))?;
 						
 						let bytes = blob.content ().to_vec ();
 						
-						return Ok (Response::builder ()
-						.body (Body::from (bytes))?);
+						let builder = Response::builder ()
+						.header ("content-type", content_type::guess (&path, Some (&bytes)))
+						.header ("x-content-type-options", "nosniff")
+						.header ("etag", &etag)
+						.header ("cache-control", cache_control)
+						.header ("content-security-policy", GAME_CSP)
+						.header ("cross-origin-opener-policy", "same-origin")
+						// The sandbox makes the game's own fetches cross-origin
+						.header ("access-control-allow-origin", "*");
+						
+						blob_response = Some ((builder, etag, bytes));
 					},
-					_ => bail! ("Git object is unknown type"),
+					_ => return Err (Error::not_found ("That path is a submodule, which we can't show")),
 				}
 			}
 		}
 		
+		if let Some ((builder, etag, bytes)) = blob_response {
+			return Ok (range::respond (headers, Some (&etag), builder, Source::Memory (bytes)).await?);
+		}
+		
+		// Only listings count, the game's own files shouldn't
+		self.rate_limits.git_per_ip.check (&client.ip.to_string ())?;
+		let (entries, readme) = {
+			let (root, dir) = (root.clone (), dir.clone ());
+			tokio::task::spawn_blocking (move || list_dir (commit_oid, &path, &root, &dir)).await??
+		};
+		
 		let page = CommitPage {
+			crumbs: crumbs (&root, &commit_id, &dirs),
+			root,
+			id_short: commit_id [0..8].to_string (),
 			commit_id,
-			entries,
+			dir,
+			entries: entries.into_iter ()
+			.map (|entry| ListedEntry {
+				href: safe_path::url_segment (&entry.name),
+				entry,
+			})
+			.collect (),
+			readme,
 		};
 		
-		self.template_response ("handlebars/tree.hbs", &page).await
+		self.template_response ("tree", &page).await
 	}
 	
-	async fn handle_static (&self, path: &str) -> ResultResponse
+	/// A directory listing as JSON, with the same fields as the HTML one
+	async fn handle_api_tree (&self, tail: &str) -> ResultResponse
 	{
-		let bytes = tokio::fs::read (Path::new ("static/").join (path)).await
-		.with_context (|| format! ("Failed to open static file"))?;
+		#[derive (Serialize)]
+		struct Listing {
+			commit_id: String,
+			path: String,
+			entries: Vec <tree::Entry>,
+		}
+		
+		let (rev, path) = match tail.split_once ('/') {
+			Some ((rev, rel)) => (rev, safe_path::relative_path (rel)?),
+			None => (tail, PathBuf::new ()),
+		};
+		
+		let listing = {
+			let rev = rev.to_string ();
+			tokio::task::spawn_blocking (move || -> Result <_, Error> {
+				let repo = Repository::open (REPO_PATH)?;
+				let commit = resolve_commit (&repo, &rev)?;
+				Ok (Listing {
+					commit_id: commit.id ().to_string (),
+					path: path.display ().to_string (),
+					entries: tree::list (&repo, commit.id (), &path)?,
+				})
+			}).await??
+		};
+		
+		let cache_control = match rev == listing.commit_id {
+			true => http_cache::IMMUTABLE,
+			false => http_cache::REVALIDATE,
+		};
 		
 		Ok (Response::builder ()
-		.body (Body::from (bytes))?)
+		.header ("content-type", "application/json; charset=utf-8")
+		.header ("x-content-type-options", "nosniff")
+		.header ("cache-control", cache_control)
+		.header ("access-control-allow-origin", "*")
+		.body (Body::from (serde_json::to_vec (&listing)?))?)
 	}
 	
-	async fn template_response <P: AsRef <Path> + Debug, T: Serialize> (
-		&self,
-		path: P,
-		data: &T
-	) -> ResultResponse 
+	/// A commit's files as a `.tar.gz` or `.zip`, written straight into
+	/// the response as it's sent
+	async fn handle_archive (&self, tail: &str) -> ResultResponse
 	{
-		let template = tokio::fs::read_to_string (&path).await
-		.with_context (|| format! ("Failed to load Handlebars template `{:?}`", &path))?;
-		let body = self.handlebars.render_template (&template, data)
-		.with_context (|| format! ("Failed to render Handlebars template `{:?}`", &path))?;
+		let (rev, format) = archive::Format::split (tail)
+		.ok_or_else (|| Error::not_found ("Archives are `.tar.gz` or `.zip`, like `/archive/main.zip`"))?;
 		
-		let bytes = body.as_bytes ().to_vec ();
+		let commit_id = {
+			let repo = Repository::open (REPO_PATH)?;
+			let commit = resolve_commit (&repo, rev)?;
+			commit.id ()
+		};
+		let id_short = commit_id.to_string () [0..8].to_string ();
+		
+		let cache_control = match rev == commit_id.to_string () {
+			true => http_cache::IMMUTABLE,
+			false => http_cache::REVALIDATE,
+		};
+		
+		let (tx, rx) = tokio::sync::mpsc::channel (4);
+		let prefix = id_short.clone ();
+		tokio::task::spawn_blocking (move || {
+			let result = Repository::open (REPO_PATH)
+			.map_err (anyhow::Error::from)
+			.and_then (|repo| archive::write (&repo, commit_id, &prefix, format, archive::BodyWriter::new (tx.clone ())));
+			
+			// The headers are long gone, so all that's left is to cut the
+			// body short
+			if let Err (e) = result {
+				tracing::warn! ("Archive of {} stopped: {:?}", commit_id, e);
+				tx.blocking_send (Err (std::io::Error::other ("Archive failed"))).ok ();
+			}
+		});
 		
 		Ok (Response::builder ()
-		.body (Body::from (bytes))?)
+		.header ("content-type", format.content_type ())
+		.header ("content-disposition", format! ("attachment; filename=\"{}.{}\"", id_short, format.extension ()))
+		.header ("x-content-type-options", "nosniff")
+		.header ("cache-control", cache_control)
+		.body (Body::wrap_stream (tokio_stream::wrappers::ReceiverStream::new (rx)))?)
 	}
-}
-
-struct CommitData {
-	id: String,
-	author: Option <String>,
-	time: String,
-	message: Option <String>,
-}
-
-fn get_last_commits (n: usize) -> anyhow::Result <Vec <CommitData>> {
-	use chrono::{DateTime, NaiveDateTime, Utc};
 	
-	let mut commits = vec! [];
+	/// Wraps a commit's `game.html` in a sandboxed iframe, so the game runs
+	/// without access to codepong's forms or cookies
+	async fn handle_play (&self, tail: &str) -> ResultResponse
+	{
+		#[derive (Serialize)]
+		struct Page {
+			root: &'static str,
+			commit_id: String,
+			id_short: String,
+		}
+		
+		// Links in the page are relative, so they need to know how deep
+		// they are
+		let (rev, root) = match tail.strip_suffix ('/') {
+			Some (rev) => (rev, "../../"),
+			None => (tail, "../"),
+		};
+		
+		let commit_id = {
+			let repo = Repository::open (REPO_PATH)?;
+			let commit = resolve_commit (&repo, rev)?;
+			
+			let has_game = commit.tree ()?.get_path (Path::new ("game.html")).is_ok ();
+			if ! has_game {
+				return Err (Error::not_found (format! ("Commit {} doesn't have a game.html", commit.id ())));
+			}
+			
+			commit.id ().to_string ()
+		};
+		
+		let page = Page {
+			root,
+			id_short: commit_id [0..8].to_string (),
+			commit_id,
+		};
+		
+		self.template_response ("play", &page).await
+	}
 	
-	// I want the repo to be dropped before I start rendering the body
-	let repo = Repository::open (REPO_PATH)?;
+	/// A file as a page, with highlighting and line numbers, or rendered
+	/// if it's Markdown. `/tree/` still serves the raw file, which is what
+	/// the game loads.
+	async fn handle_source (&self, tail: &str, query: Option <&str>) -> ResultResponse
+	{
+		#[derive (Deserialize)]
+		struct Query {
+			#[serde (default)]
+			plain: bool,
+		}
+		
+		#[derive (Serialize)]
+		struct Page {
+			root: String,
+			commit_id: String,
+			id_short: String,
+			path: String,
+			crumbs: Vec <Crumb>,
+			file_name: String,
+			size: String,
+			content_type: &'static str,
+			language: Option <&'static str>,
+			lines: Vec <Line>,
+			note: Option <&'static str>,
+			is_markdown: bool,
+			markdown: Option <String>,
+		}
+		
+		#[derive (Serialize)]
+		struct Line {
+			number: usize,
+			html: String,
+		}
+		
+		let (rev, path) = split_rev_path (tail)?;
+		let query: Query = serde_urlencoded::from_str (query.unwrap_or (""))
+		.map_err (|_| Error::bad_request ("Bad query string"))?;
+		let root = "../".repeat (1 + tail.matches ('/').count ());
+		
+		let (commit_id, bytes) = {
+			let repo = Repository::open (REPO_PATH)?;
+			let commit = resolve_commit (&repo, rev)?;
+			let commit_id = commit.id ().to_string ();
+			let entry = commit.tree ()?.get_path (&path)
+			.map_err (|_| Error::not_found (format! ("No file or directory at `{}` in commit {}", path.display (), commit_id)))?;
+			
+			match entry.kind () {
+				Some (git2::ObjectType::Blob) => (),
+				// Directories are listed by `/tree/`
+				Some (git2::ObjectType::Tree) => return Ok (Response::builder ()
+				.status (StatusCode::SEE_OTHER)
+				.header ("location", format! ("{}tree/{}/{}/", self.proxy.base_path (), commit_id, path.display ()))
+				.body (Body::from ("Redirecting..."))?),
+				_ => return Err (Error::not_found ("That path is a submodule, which we can't show")),
+			}
+			
+			let blob = entry.to_object (&repo)?.peel_to_blob ()?;
+			(commit_id, blob.content ().to_vec ())
+		};
+		
+		let language = highlight::Language::from_path (&path);
+		let text = std::str::from_utf8 (&bytes).ok ().filter (|_| ! bytes.contains (&0));
+		
+		let components: Vec <_> = path.iter ().map (|c| c.to_string_lossy ().to_string ()).collect ();
+		let (file_name, dirs) = components.split_last ().ok_or_else (|| Error::not_found ("No file here"))?;
+		let is_markdown = language == highlight::Language::Markdown;
+		let mut markdown = None;
+		
+		let (lines, note) = match text {
+			None => (vec! [], Some ("This is a binary file.")),
+			Some (_) if bytes.len () > highlight::MAX_BYTES => (vec! [], Some ("This file is too big to show here.")),
+			Some (text) if is_markdown && ! query.plain => {
+				let dir: String = dirs.iter ().map (|d| format! ("{}/", d)).collect ();
+				markdown = Some (markdown::render (text, &root, &commit_id, &dir));
+				(vec! [], None)
+			},
+			Some (text) => {
+				let lines = highlight::highlight (text, language)
+				.into_iter ()
+				.enumerate ()
+				.map (|(i, html)| Line {
+					number: i + 1,
+					html,
+				})
+				.collect ();
+				(lines, None)
+			},
+		};
+		
+		let page = Page {
+			crumbs: crumbs (&root, &commit_id, dirs),
+			root,
+			id_short: commit_id [0..8].to_string (),
+			commit_id,
+			path: path.display ().to_string (),
+			file_name: file_name.clone (),
+			size: human_size (bytes.len () as u64),
+			content_type: content_type::guess (&path, Some (&bytes)),
+			language: Some (language.name ()).filter (|_| note.is_none ()),
+			lines,
+			note,
+			is_markdown,
+			markdown,
+		};
+		
+		self.template_response ("source", &page).await
+	}
 	
-	let head = repo.head ()?;
-	let mut commit = head.peel_to_commit ()?;
+	/// Commits that changed one file or directory
+	async fn handle_file_history (&self, tail: &str, query: Option <&str>) -> ResultResponse
+	{
+		#[derive (Deserialize)]
+		struct Query {
+			after: Option <String>,
+		}
+		
+		#[derive (Serialize)]
+		struct Page {
+			root: String,
+			commit_id: String,
+			id_short: String,
+			path: String,
+			commits: Vec <CommitData>,
+			next_url: Option <String>,
+		}
+		
+		let (rev, path) = split_rev_path (tail)?;
+		let query: Query = serde_urlencoded::from_str (query.unwrap_or (""))
+		.map_err (|_| Error::bad_request ("Bad query string"))?;
+		let after = parse_after (query.after.as_deref ())?;
+		
+		// Walking the history reads a tree for every commit
+		let (commit_id, log, path) = {
+			let rev = rev.to_string ();
+			tokio::task::spawn_blocking (move || -> Result <_, Error> {
+				let repo = Repository::open (REPO_PATH)?;
+				let commit = resolve_commit (&repo, &rev)?;
+				if commit.tree ()?.get_path (&path).is_err () {
+					return Err (Error::not_found (format! ("No file or directory at `{}` in commit {}", path.display (), commit.id ())));
+				}
+				
+				let log = history::path_log (&repo, commit.id (), &path, after, LOG_PAGE_SIZE)?;
+				Ok ((commit.id ().to_string (), log, path))
+			}).await??
+		};
+		
+		let page = Page {
+			root: "../".repeat (1 + tail.matches ('/').count ()),
+			id_short: commit_id [0..8].to_string (),
+			commit_id,
+			path: path.display ().to_string (),
+			commits: log.commits,
+			next_url: log.next.map (|next| format! ("?after={}", next)),
+		};
+		
+		self.template_response ("history", &page).await
+	}
 	
-	let replacer = gh_emoji::Replacer::new ();
+	/// Which commit last changed each line of a file
+	async fn handle_blame (&self, tail: &str) -> ResultResponse
+	{
+		#[derive (Serialize)]
+		struct Page {
+			root: String,
+			commit_id: String,
+			id_short: String,
+			path: String,
+			lines: Vec <history::BlameLine>,
+		}
+		
+		let (rev, path) = split_rev_path (tail)?;
+		
+		let (commit_id, lines, path) = {
+			let rev = rev.to_string ();
+			tokio::task::spawn_blocking (move || -> Result <_, Error> {
+				let repo = Repository::open (REPO_PATH)?;
+				let commit = resolve_commit (&repo, &rev)?;
+				let lines = history::blame (&repo, commit.id (), &path)?;
+				Ok ((commit.id ().to_string (), lines, path))
+			}).await??
+		};
+		
+		let page = Page {
+			root: "../".repeat (1 + tail.matches ('/').count ()),
+			id_short: commit_id [0..8].to_string (),
+			commit_id,
+			path: path.display ().to_string (),
+			lines,
+		};
+		
+		self.template_response ("blame", &page).await
+	}
 	
-	for _ in 0..n {
-		let author = commit.author ().name ().map (|s| s.to_string ());
+	/// A commit's changes as a plain-text patch
+	async fn handle_diff (&self, headers: &hyper::HeaderMap, rev: &str) -> ResultResponse
+	{
+		let (commit_id, patch) = {
+			let repo = Repository::open (REPO_PATH)?;
+			let commit = resolve_commit (&repo, rev)?;
+			(commit.id ().to_string (), history::patch (&repo, commit.id ())?)
+		};
 		
-		let time = commit.time ();
-		let time = DateTime::<Utc>::from_utc (NaiveDateTime::from_timestamp (time.seconds (), 0), Utc);
+		let cache_control = match rev == commit_id {
+			true => http_cache::IMMUTABLE,
+			false => http_cache::REVALIDATE,
+		};
 		
-		let message = commit.message ()
-		.map (|s| replacer.replace_all (s).to_string ());
+		let builder = Response::builder ()
+		.header ("content-type", "text/plain; charset=utf-8")
+		.header ("x-content-type-options", "nosniff")
+		.header ("cache-control", cache_control);
 		
-		commits.push (CommitData {
-			id: commit.id ().to_string (),
-			author,
-			time: time.to_string (),
-			message,
-		});
+		Ok (range::respond (headers, None, builder, Source::Memory (patch.into_bytes ())).await?)
+	}
+	
+	async fn handle_static (&self, headers: &hyper::HeaderMap, tail: &str) -> ResultResponse
+	{
+		let path = match safe_path::resolve (Path::new ("static"), tail).await {
+			Err (UnsafePath::NotFound) => return self.handle_embedded_static (headers, tail).await,
+			x => x?,
+		};
 		
-		if commit.parent_count () != 1 {
-			// println! ("Error: Not one parent");
-			break;
+		let mut start = vec! [];
+		tokio::fs::File::open (&path).await
+		.context ("Failed to open static file")?
+		.take (content_type::SNIFF_BYTES)
+		.read_to_end (&mut start).await?;
+		let content_type = content_type::guess_from_start (&path, &start);
+		
+		// Prefer a precompressed variant next to the file, like
+		// `style.css.br`, so we don't compress the same file every time
+		let mut file_path = path;
+		let mut encoding = None;
+		
+		if compression::is_compressible (content_type) {
+			for enc in compression::negotiate (headers) {
+				let variant = format! ("{}.{}", tail, enc.extension ());
+				if let Ok (p) = safe_path::resolve (Path::new ("static"), &variant).await {
+					file_path = p;
+					encoding = Some (enc);
+					break;
+				}
+			}
 		}
-		else {
-			commit = commit.parent (0)?;
+		
+		let file = tokio::fs::File::open (&file_path).await
+		.context ("Failed to open static file")?;
+		let metadata = file.metadata ().await?;
+		let modified = metadata.modified ()?;
+		let etag = http_cache::file_etag (metadata.len (), modified);
+		
+		if http_cache::etag_matches (headers, &etag) || http_cache::not_modified_since (headers, modified) {
+			return Ok (http_cache::not_modified (&etag, http_cache::REVALIDATE)?);
+		}
+		
+		let mut builder = Response::builder ()
+		.header ("content-type", content_type)
+		.header ("x-content-type-options", "nosniff")
+		.header ("etag", &etag)
+		.header ("last-modified", http_cache::http_date (modified))
+		.header ("cache-control", http_cache::REVALIDATE);
+		
+		if let Some (enc) = encoding {
+			builder = builder.header ("content-encoding", enc.name ());
 		}
+		
+		Ok (range::respond (headers, Some (&etag), builder, Source::File (file)).await?)
 	}
 	
-	Ok (commits)
+	/// Serves static files that were built into the binary, for when
+	/// they're not on disk
+	async fn handle_embedded_static (&self, headers: &hyper::HeaderMap, tail: &str) -> ResultResponse
+	{
+		let rel_path = safe_path::relative_path (tail)?;
+		let rel_path = rel_path.to_str ().ok_or (UnsafePath::Malformed)?;
+		let plain = assets::static_file (rel_path).ok_or (UnsafePath::NotFound)?;
+		let content_type = content_type::guess (Path::new (rel_path), Some (&plain.data));
+		
+		let mut encoding = None;
+		let mut file = None;
+		
+		if compression::is_compressible (content_type) {
+			for enc in compression::negotiate (headers) {
+				file = assets::static_file (&format! ("{}.{}", rel_path, enc.extension ()));
+				if file.is_some () {
+					encoding = Some (enc);
+					break;
+				}
+			}
+		}
+		
+		let file = file.unwrap_or (plain);
+		
+		let fresh = http_cache::etag_matches (headers, &file.etag) ||
+			file.modified.map (|t| http_cache::not_modified_since (headers, t)).unwrap_or (false);
+		if fresh {
+			return Ok (http_cache::not_modified (&file.etag, http_cache::REVALIDATE)?);
+		}
+		
+		let mut builder = Response::builder ()
+		.header ("content-type", content_type)
+		.header ("x-content-type-options", "nosniff")
+		.header ("etag", &file.etag)
+		.header ("cache-control", http_cache::REVALIDATE);
+		
+		if let Some (modified) = file.modified {
+			builder = builder.header ("last-modified", http_cache::http_date (modified));
+		}
+		if let Some (enc) = encoding {
+			builder = builder.header ("content-encoding", enc.name ());
+		}
+		
+		Ok (range::respond (headers, Some (&file.etag), builder, Source::Memory (file.data.into_owned ())).await?)
+	}
+	
+	async fn template_response <T: Serialize> (
+		&self,
+		name: &str,
+		data: &T
+	) -> ResultResponse 
+	{
+		let body = self.templates.render (name, data)?;
+		
+		let bytes = body.as_bytes ().to_vec ();
+		
+		Ok (Response::builder ()
+		.header ("content-type", "text/html; charset=utf-8")
+		.header ("content-length", bytes.len ())
+		.header ("x-content-type-options", "nosniff")
+		.header ("content-security-policy", PAGE_CSP)
+		.header ("cross-origin-opener-policy", "same-origin")
+		.body (Body::from (bytes))?)
+	}
+	
+	/// Turns a handler's error into an HTML error page. Internal errors
+	/// are logged, but their details are kept away from the client.
+	async fn error_response (&self, e: Error) -> ResponseB
+	{
+		#[derive (Serialize)]
+		struct Page <'a> {
+			status: u16,
+			reason: &'a str,
+			message: &'a str,
+		}
+		
+		match &e {
+			Error::Internal (_) => tracing::error! ("{}", e),
+			_ => tracing::debug! ("{}", e),
+		}
+		
+		let status = e.status_code ();
+		let page = Page {
+			status: status.as_u16 (),
+			reason: status.canonical_reason ().unwrap_or ("Error"),
+			message: e.message (),
+		};
+		
+		let mut resp = match self.template_response ("error", &page).await {
+			Ok (x) => x,
+			Err (render_err) => {
+				tracing::error! ("Couldn't render error page: {}", render_err);
+				Response::new (Body::from (format! ("{} {}", page.status, page.reason)))
+			},
+		};
+		*resp.status_mut () = status;
+		
+		if let Error::TooManyRequests (retry_after) = e {
+			resp.headers_mut ().insert ("retry-after", retry_after.into ());
+		}
+		
+		resp
+	}
+}
+
+/// Relative link to a page of `/log`
+fn log_url (branch: Option <&str>, after: Option <git2::Oid>, first_parent: bool) -> String
+{
+	let mut params = vec! [];
+	if let Some (branch) = branch {
+		params.push (("branch", branch.to_string ()));
+	}
+	if let Some (after) = after {
+		params.push (("after", after.to_string ()));
+	}
+	if first_parent {
+		params.push (("first_parent", "true".to_string ()));
+	}
+	
+	match serde_urlencoded::to_string (&params) {
+		Ok (q) if ! q.is_empty () => format! ("log?{}", q),
+		_ => "log".to_string (),
+	}
 }
 
-async fn read_body_limited (mut body: Body, limit: usize) -> anyhow::Result <Vec <u8>>
+/// Links to each of `dirs`, from the top down
+fn crumbs (root: &str, commit_id: &str, dirs: &[String]) -> Vec <Crumb>
+{
+	let mut url = format! ("{}tree/{}/", root, commit_id);
+	
+	dirs.iter ()
+	.map (|name| {
+		url.push_str (&safe_path::url_segment (name));
+		url.push ('/');
+		Crumb {
+			name: name.clone (),
+			url: url.clone (),
+		}
+	})
+	.collect ()
+}
+
+/// Checks that `fetched` in the quarantine is a fast-forward of `branch`,
+/// then moves its objects into the real repo and advances the branch
+fn fast_forward (quarantine: Quarantine, fetched: git2::Oid, branch: &str, source: &str, log_message: &str) -> Result <(), Error>
+{
+	// The quarantine sees the real repo's objects too, including any
+	// commits made since it was started
+	let head = Repository::open (REPO_PATH)?.refname_to_id (&format! ("refs/heads/{}", branch))?;
+	
+	{
+		let quarantined = Repository::open (quarantine.path ())?;
+		quarantined.find_commit (fetched)?;
+		
+		if fetched == head || quarantined.graph_descendant_of (head, fetched)? {
+			return Err (Error::bad_request (format! ("Already up-to-date with `{}` from {}", branch, source)));
+		}
+		if ! quarantined.graph_descendant_of (fetched, head)? {
+			return Err (Error::bad_request (format! ("Cannot fast-forward merge to `{}` from {}", branch, source)));
+		}
+	}
+	
+	quarantine.accept ()?;
+	
+	let repo = Repository::open (REPO_PATH)?;
+	git_repo::advance (&repo, branch, head, fetched, log_message)
+}
+
+/// A directory's entries and its README, rendered. Finding each entry's
+/// last commit walks the history, so this runs in `spawn_blocking`.
+fn list_dir (commit: git2::Oid, path: &Path, root: &str, dir: &str) -> Result <(Vec <tree::Entry>, Option <Readme>), Error>
+{
+	let repo = Repository::open (REPO_PATH)?;
+	let entries = tree::list (&repo, commit, path)?;
+	
+	let readme = match tree::readme (&entries) {
+		None => None,
+		Some (entry) => {
+			let blob = repo.find_commit (commit)?.tree ()?
+			.get_path (&path.join (&entry.name))?
+			.to_object (&repo)?
+			.peel_to_blob ()?;
+			
+			std::str::from_utf8 (blob.content ()).ok ()
+			.filter (|_| blob.size () <= highlight::MAX_BYTES)
+			.map (|text| Readme {
+				name: entry.name.clone (),
+				html: markdown::render (text, root, &commit.to_string (), dir),
+			})
+		},
+	};
+	
+	Ok ((entries, readme))
+}
+
+/// A file size the way people read them
+fn human_size (bytes: u64) -> String
+{
+	match bytes {
+		0..=1023 => format! ("{} bytes", bytes),
+		1024..=1_048_575 => format! ("{:.1} KiB", bytes as f64 / 1024.0),
+		_ => format! ("{:.1} MiB", bytes as f64 / 1_048_576.0),
+	}
+}
+
+/// The `after` cursor of a paged log, which is always a full commit ID
+fn parse_after (after: Option <&str>) -> Result <Option <git2::Oid>, Error>
+{
+	match after {
+		None => Ok (None),
+		Some (x) if x.len () == 40 => git2::Oid::from_str (x).map (Some).map_err (|_| Error::bad_request ("Bad `after` commit ID")),
+		Some (_) => Err (Error::bad_request ("Bad `after` commit ID")),
+	}
+}
+
+/// Splits `/history/` and `/blame/` URLs into the commit and the path
+/// inside it
+fn split_rev_path (tail: &str) -> Result <(&str, PathBuf), Error>
+{
+	let (rev, path) = tail.split_once ('/')
+	.ok_or_else (|| Error::not_found ("Needs a commit and a path, like `main/game.html`"))?;
+	let path = safe_path::relative_path (path)?;
+	if path.as_os_str ().is_empty () {
+		return Err (Error::not_found ("Needs a commit and a path, like `main/game.html`"));
+	}
+	
+	Ok ((rev, path))
+}
+
+/// Missing or broken fields in a urlencoded form are the client's fault,
+/// and serde's message, like ``missing field `username` ``, is safe to show
+fn form_error (e: serde_urlencoded::de::Error) -> Error
+{
+	Error::bad_request (format! ("Couldn't read the form: {}", e))
+}
+
+async fn read_body_limited (mut body: Body, limit: usize) -> Result <Vec <u8>, Error>
 {
 	use futures_util::StreamExt;
 	
 	let mut buffer = vec! [];
 	while let Some (chunk) = body.next ().await {
-		let chunk = chunk?;
+		let chunk = chunk.map_err (|_| Error::bad_request ("Couldn't read the request body"))?;
 		
 		if buffer.len () + chunk.len () > limit {
-			bail! ("Body was bigger than limit");
+			return Err (Error::payload_too_large (format! ("That form is bigger than {} bytes", limit)));
 		}
 		
 		buffer.extend_from_slice (&chunk);
@@ -782,10 +1827,197 @@ async fn read_body_limited (mut body: Body, limit: usize) -> anyhow::Result <Vec
 	Ok (buffer)
 }
 
-fn handle_error (status_code: StatusCode, s: &'static str) -> ResultResponse {
-	Ok (Response::builder ()
-	.status (status_code)
-	.body (Body::from (s))?)
+/// The commit form. Browsers send it as multipart when there's a file
+/// in it, and urlencoded otherwise.
+#[derive (Default, Deserialize)]
+struct CommitForm {
+	username: String,
+	#[serde (default)]
+	url: String,
+	#[serde (default)]
+	csrf_token: String,
+	#[serde (skip)]
+	bundle: Vec <u8>,
+	
+	/// `git format-patch` output, pasted or uploaded
+	#[serde (default)]
+	patch: String,
+	
+	/// Empty for the main branch
+	#[serde (default)]
+	branch: String,
+}
+
+/// The big parts of a multipart commit form, not read yet
+struct Uploads {
+	multipart: multer::Multipart <'static>,
+	first: multer::Field <'static>,
+}
+
+/// Fields that can be as big as a fetch. Our form puts them after the
+/// small ones.
+const UPLOAD_FIELDS: &[&str] = &["bundle", "patch", "patch_file"];
+
+fn bad_multipart (e: multer::Error) -> Error
+{
+	Error::bad_request (format! ("Couldn't read the form: {}", e))
+}
+
+/// Reads the commit form up to its first upload, so the caller can check
+/// the token, the rate limit and the baton before taking in megabytes
+async fn read_commit_form (headers: &hyper::HeaderMap, body: Body, max_bytes: u64) -> Result <(CommitForm, Option <Uploads>), Error>
+{
+	let boundary = headers.get ("content-type")
+	.and_then (|v| v.to_str ().ok ())
+	.and_then (|v| multer::parse_boundary (v).ok ());
+	
+	let boundary = match boundary {
+		None => {
+			let form_data = read_body_limited (body, 1_024).await?;
+			let form = serde_urlencoded::from_bytes (&form_data).map_err (form_error)?;
+			return Ok ((form, None));
+		},
+		Some (x) => x,
+	};
+	
+	let mut size_limit = multer::SizeLimit::new ()
+	.whole_stream (max_bytes)
+	.per_field (1_024);
+	for name in UPLOAD_FIELDS {
+		size_limit = size_limit.for_field (*name, max_bytes);
+	}
+	
+	let constraints = multer::Constraints::new ()
+	.allowed_fields (vec! ["username", "url", "csrf_token", "branch", "bundle", "patch", "patch_file"])
+	.size_limit (size_limit);
+	let mut multipart = multer::Multipart::with_constraints (body, boundary, constraints);
+	let mut form = CommitForm::default ();
+	
+	while let Some (field) = multipart.next_field ().await.map_err (bad_multipart)? {
+		let name = field.name ().unwrap_or_default ().to_string ();
+		if UPLOAD_FIELDS.contains (&name.as_str ()) {
+			return Ok ((form, Some (Uploads {
+				multipart,
+				first: field,
+			})));
+		}
+		
+		let bytes = field.bytes ().await.map_err (bad_multipart)?;
+		let text = String::from_utf8 (bytes.to_vec ())
+		.map_err (|_| Error::bad_request (format! ("The {} field isn't UTF-8", name)))?;
+		match name.as_str () {
+			"username" => form.username = text,
+			"url" => form.url = text,
+			"csrf_token" => form.csrf_token = text,
+			_ => form.branch = text,
+		}
+	}
+	
+	Ok ((form, None))
+}
+
+/// Reads the rest of the commit form. The small fields were already
+/// checked, so they can't show up again down here.
+async fn read_uploads (form: &mut CommitForm, uploads: Uploads) -> Result <(), Error>
+{
+	let Uploads {
+		mut multipart,
+		first,
+	} = uploads;
+	let mut field = Some (first);
+	
+	while let Some (f) = field {
+		let name = f.name ().unwrap_or_default ().to_string ();
+		if ! UPLOAD_FIELDS.contains (&name.as_str ()) {
+			return Err (Error::bad_request (format! ("The {} field has to come before the uploads", name)));
+		}
+		
+		let bytes = f.bytes ().await.map_err (bad_multipart)?;
+		
+		if name == "bundle" {
+			form.bundle = bytes.to_vec ();
+		}
+		else {
+			let text = String::from_utf8 (bytes.to_vec ())
+			.map_err (|_| Error::bad_request (format! ("The {} field isn't UTF-8", name)))?;
+			match name.as_str () {
+				// Browsers send textareas with CRLF line endings
+				"patch" => form.patch.push_str (&text.replace ("\r\n", "\n")),
+				_ => form.patch.push_str (&text),
+			}
+		}
+		
+		field = multipart.next_field ().await.map_err (bad_multipart)?;
+	}
+	
+	Ok (())
+}
+
+/// Pages with a CSRF token in them are per-browser, so they must not be
+/// cached, and may have to start a session
+fn with_session (mut resp: ResponseB, set_cookie: Option <String>) -> ResponseB
+{
+	let headers = resp.headers_mut ();
+	headers.insert ("cache-control", hyper::header::HeaderValue::from_static ("no-store"));
+	
+	if let Some (cookie) = set_cookie.and_then (|c| hyper::header::HeaderValue::from_str (&c).ok ()) {
+		headers.append ("set-cookie", cookie);
+	}
+	
+	resp
+}
+
+/// Content types that Git's own HTTP backend uses for dumb-protocol files
+fn git_content_type (tail: &str) -> &'static str {
+	if tail.ends_with (".pack") {
+		"application/x-git-packed-objects"
+	}
+	else if tail.ends_with (".idx") {
+		"application/x-git-packed-objects-toc"
+	}
+	else if tail.starts_with ("objects/") && ! tail.starts_with ("objects/info/") {
+		"application/x-git-loose-object"
+	}
+	else {
+		"text/plain; charset=utf-8"
+	}
+}
+
+/// Checks that a commit ID, branch or tag name from a URL is plain enough
+/// to hand to `revparse_single` without triggering any of its fancier
+/// syntax like `HEAD~2` or `:/message`
+fn is_valid_rev (rev: &str) -> bool {
+	! rev.is_empty () &&
+	rev.len () <= 255 &&
+	! rev.starts_with (['-', '.']) &&
+	! rev.ends_with (".lock") &&
+	! rev.contains ("..") &&
+	rev.bytes ().all (|b| b.is_ascii_alphanumeric () || b"._-".contains (&b))
+}
+
+fn bad_rev (rev: &str) -> Error {
+	Error::bad_request (format! ("`{}` isn't a valid commit ID, branch or tag name", rev))
+}
+
+/// Finds the commit that a full or short commit ID, branch or tag name
+/// points to
+fn resolve_commit <'r> (repo: &'r Repository, rev: &str) -> Result <git2::Commit <'r>, Error> {
+	use git2::ErrorCode;
+	
+	if ! is_valid_rev (rev) {
+		return Err (bad_rev (rev));
+	}
+	
+	let obj = repo.revparse_single (rev)
+	.map_err (|e| match e.code () {
+		ErrorCode::NotFound => Error::not_found (format! ("There's no commit, branch or tag called `{}`", rev)),
+		ErrorCode::Ambiguous => Error::bad_request (format! ("The short commit ID `{}` is ambiguous", rev)),
+		ErrorCode::InvalidSpec => bad_rev (rev),
+		_ => e.into (),
+	})?;
+	
+	obj.peel_to_commit ()
+	.map_err (|_| Error::not_found (format! ("`{}` doesn't point to a commit", rev)))
 }
 
 #[derive (Debug, PartialEq)]
@@ -803,21 +2035,15 @@ fn parse_irc_privmsg (robot_nick: &str, line: &str) -> Option <BotCommand>
 		if let Some (line) = line.strip_prefix (": ") {
 			line
 		}
-		else if let Some (line) = line.strip_prefix (" ") {
-			line
-		}
 		else {
-			return None;
+			line.strip_prefix (" ")?
 		}
 	}
 	else if let Some (line) = line.strip_prefix ("! ") {
 		line
 	}
-	else if let Some (line) = line.strip_prefix ("!") {
-		line
-	}
 	else {
-		return None;
+		line.strip_prefix ("!")?
 	};
 	
 	match line {
@@ -849,4 +2075,164 @@ mod tests {
 			assert_eq! (actual, expected);
 		}
 	}
+	
+	#[test]
+	fn tree_revs () {
+		use super::{Error, resolve_commit};
+		
+		let dir = tempfile::tempdir ().unwrap ();
+		let repo = git2::Repository::init (dir.path ()).unwrap ();
+		let sig = git2::Signature::now ("test", "test@example.com").unwrap ();
+		let tree = repo.find_tree (repo.index ().unwrap ().write_tree ().unwrap ()).unwrap ();
+		let oid = repo.commit (Some ("refs/heads/main"), &sig, &sig, "First", &tree, &[]).unwrap ();
+		repo.tag_lightweight ("v1", &repo.find_object (oid, None).unwrap (), false).unwrap ();
+		
+		let id = oid.to_string ();
+		for rev in &[&id [..], &id [..7], "main", "v1"] {
+			assert_eq! (resolve_commit (&repo, rev).unwrap ().id (), oid);
+		}
+		
+		for rev in &["nope", "0000000"] {
+			assert! (matches! (resolve_commit (&repo, rev), Err (Error::NotFound (_))), "{}", rev);
+		}
+		
+		for rev in &["", "HEAD~1", "main^", "main..v1", "-x", ":/First", "main@{0}", "a/b", "a.lock"] {
+			assert! (matches! (resolve_commit (&repo, rev), Err (Error::BadRequest (_))), "{}", rev);
+		}
+	}
+	
+	/// A server with no IRC and no branches, for sending requests
+	/// straight to the router
+	fn test_server () -> super::CodePongServer {
+		use super::*;
+		
+		CodePongServer {
+			templates: Arc::new (Templates::load ().unwrap ()),
+			proxy: Proxy::new (&Default::default ()),
+			csrf: Csrf::new ().unwrap (),
+			rate_limits: RateLimits::new (&Default::default ()),
+			fetch_config: Default::default (),
+			irc_outbox: IrcOutbox::closed (),
+			branches: Arc::new (vec! []),
+		}
+	}
+	
+	fn test_client () -> super::Client {
+		super::Client {
+			ip: [127, 0, 0, 1].into (),
+			https: false,
+		}
+	}
+	
+	/// Sends hostile URIs through the router, so a handler that forgets
+	/// to go through `safe_path` is caught too. Tests run from the crate
+	/// root, so `/static/..%2fCargo.toml` would find a real file if it
+	/// got through.
+	#[tokio::test]
+	async fn traversal_routes () {
+		use super::*;
+		
+		let server = test_server ();
+		let client = test_client ();
+		
+		let get = |uri: &str| hyper::Request::get (uri).body (Body::empty ()).unwrap ();
+		
+		let resp = server.route (get ("/static/css/style.css"), client).await.unwrap ();
+		assert_eq! (resp.status (), StatusCode::OK);
+		
+		for route in &["/static/", "/git/", "/tree/main/"] {
+			for tail in &[
+				"..%2fCargo.toml",
+				"../Cargo.toml",
+				"%2e%2e/Cargo.toml",
+				"css%2f..%2f..%2fCargo.toml",
+				"..%2f..%2f..%2fCargo.toml",
+				"%2fetc%2fpasswd",
+				"..%5cCargo.toml",
+			] {
+				let uri = format! ("{}{}", route, tail);
+				match server.route (get (&uri), client).await {
+					Ok (resp) => panic! ("{} gave {}", uri, resp.status ()),
+					Err (e) => assert_eq! (e.status_code (), StatusCode::BAD_REQUEST, "{}", uri),
+				}
+			}
+		}
+	}
+	
+	#[tokio::test]
+	async fn head_keeps_length () {
+		use super::*;
+		
+		let server = test_server ();
+		let len = std::fs::metadata ("static/css/style.css").unwrap ().len ();
+		let req = |method: Method| hyper::Request::builder ()
+		.method (method)
+		.uri ("/static/css/style.css")
+		.header ("accept-encoding", "gzip")
+		.body (Body::empty ())
+		.unwrap ();
+		
+		let get = server.handle_all (req (Method::GET), test_client ()).await.unwrap ();
+		assert_eq! (get.headers ().get ("content-encoding").unwrap (), "gzip");
+		
+		let head = server.handle_all (req (Method::HEAD), test_client ()).await.unwrap ();
+		assert_eq! (head.status (), StatusCode::OK);
+		assert! (head.headers ().get ("content-encoding").is_none ());
+		assert_eq! (head.headers ().get ("content-length").unwrap (), &len.to_string ());
+		assert_eq! (head.headers ().get ("vary").unwrap (), "accept-encoding");
+		assert! (hyper::body::to_bytes (head.into_body ()).await.unwrap ().is_empty ());
+	}
+	
+	#[tokio::test]
+	async fn bad_forms () {
+		use super::*;
+		
+		let server = test_server ();
+		let post = |uri: &str, body: Vec <u8>| hyper::Request::post (uri)
+		.header ("content-type", "application/x-www-form-urlencoded")
+		.body (Body::from (body))
+		.unwrap ();
+		
+		for (uri, body, status) in [
+			("/next", b"csrf_token=x".to_vec (), StatusCode::BAD_REQUEST),
+			("/next", b"username=a&username=b".to_vec (), StatusCode::BAD_REQUEST),
+			("/next", vec! [b'a'; 2_000], StatusCode::PAYLOAD_TOO_LARGE),
+			("/commit", b"url=https%3A%2F%2Fexample.com%2F".to_vec (), StatusCode::BAD_REQUEST),
+		] {
+			match server.route (post (uri, body), test_client ()).await {
+				Ok (resp) => panic! ("{} gave {}", uri, resp.status ()),
+				Err (e) => assert_eq! (e.status_code (), status, "{}: {}", uri, e),
+			}
+		}
+	}
+	
+	/// The token is checked before the upload is read. The body stalls
+	/// in the middle of the bundle, so reading it would never finish.
+	#[tokio::test]
+	async fn commit_checks_before_upload () {
+		use futures::StreamExt;
+		use super::*;
+		
+		let server = test_server ();
+		let fields = "--x\r\n\
+		Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\nnope\r\n\
+		--x\r\n\
+		Content-Disposition: form-data; name=\"username\"\r\n\r\nalice\r\n\
+		--x\r\n\
+		Content-Disposition: form-data; name=\"bundle\"; filename=\"pong.bundle\"\r\n\r\n\
+		# v2 git bundle\n";
+		let stalled = futures::stream::iter (vec! [Ok::<_, std::io::Error> (fields)])
+		.chain (futures::stream::pending ());
+		
+		let req = hyper::Request::post ("/commit")
+		.header ("content-type", "multipart/form-data; boundary=x")
+		.body (Body::wrap_stream (stalled))
+		.unwrap ();
+		
+		match tokio::time::timeout (Duration::from_secs (5), server.route (req, test_client ())).await {
+			Err (_) => panic! ("Waited for the upload"),
+			Ok (Ok (resp)) => panic! ("Got {}", resp.status ()),
+			Ok (Err (e)) => assert_eq! (e.status_code (), StatusCode::FORBIDDEN, "{}", e),
+		}
+	}
 }
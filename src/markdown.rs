@@ -0,0 +1,94 @@
+use pulldown_cmark::{
+	CowStr,
+	Event,
+	LinkType,
+	Options,
+	Parser,
+	Tag,
+};
+
+/// Schemes that links and images may use. Anything else, like
+/// `javascript:`, is replaced with a dead link.
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Renders Markdown from the game repo as HTML that's safe to put in our
+/// own pages. Raw HTML in it is shown as text, not passed through.
+///
+/// Relative links point to the `/source/` view of the same commit, and
+/// relative images to the raw file in `/tree/`, both relative to `dir`,
+/// the directory the Markdown is in. A leading `/` means the top of the
+/// repo, like on other forges.
+pub fn render (source: &str, root: &str, commit_id: &str, dir: &str) -> String {
+	let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+	
+	let rewrite = |url: CowStr <'_>, route: &str| -> CowStr <'static> {
+		let url = url.into_string ();
+		let top = format! ("{}{}/{}/", root, route, commit_id);
+		
+		let rewritten = if url.starts_with ('#') || url.starts_with ("//") {
+			url
+		}
+		else if let Some (rest) = url.strip_prefix ('/') {
+			format! ("{}{}", top, rest)
+		}
+		else {
+			// Anything before the first `/`, `?` or `#` with a colon in it
+			// is a scheme
+			match url.split (['/', '?', '#']).next ().and_then (|first| first.split_once (':')) {
+				Some ((scheme, _)) if SAFE_SCHEMES.iter ().any (|s| s.eq_ignore_ascii_case (scheme)) => url,
+				Some (_) => "#".to_string (),
+				None => format! ("{}{}{}", top, dir, url),
+			}
+		};
+		
+		rewritten.into ()
+	};
+	
+	let parser = Parser::new_ext (source, options)
+	.map (|event| match event {
+		Event::Html (html) => Event::Text (html),
+		// The renderer adds `mailto:` to email links itself
+		Event::Start (Tag::Link (kind, url, title)) if kind != LinkType::Email => Event::Start (Tag::Link (kind, rewrite (url, "source"), title)),
+		Event::Start (Tag::Image (kind, url, title)) => Event::Start (Tag::Image (kind, rewrite (url, "tree"), title)),
+		event => event,
+	});
+	
+	let mut html = String::new ();
+	pulldown_cmark::html::push_html (&mut html, parser);
+	html
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn rendering () {
+		let render = |source: &str| render (source, "../../", "abc", "docs/");
+		
+		assert_eq! (render ("# Pong\n\nUse *W* and *S*"), "<h1>Pong</h1>\n<p>Use <em>W</em> and <em>S</em></p>\n");
+		
+		// Raw HTML is just text
+		assert_eq! (render ("<script>alert (1)</script>"), "&lt;script&gt;alert (1)&lt;/script&gt;");
+		assert_eq! (render ("Hi <img src=x onerror=alert(1)>"), "<p>Hi &lt;img src=x onerror=alert(1)&gt;</p>\n");
+		
+		for (link, href) in vec! [
+			("controls.md", "../../source/abc/docs/controls.md"),
+			("../README.md#turns", "../../source/abc/docs/../README.md#turns"),
+			("/js/pong.js", "../../source/abc/js/pong.js"),
+			("#turns", "#turns"),
+			("https://example.com/?a=1&b=2", "https://example.com/?a=1&amp;b=2"),
+			("mailto:alice@example.com", "mailto:alice@example.com"),
+			("javascript:alert(1)", "#"),
+			("JavaScript:alert(1)", "#"),
+			("java&#9;script:alert(1)", "#"),
+			("data:text/html,hi", "#"),
+		] {
+			assert_eq! (render (&format! ("[x]({})", link)), format! ("<p><a href=\"{}\">x</a></p>\n", href), "{}", link);
+		}
+		
+		assert_eq! (render ("![ball](img/ball.png \"Ball\")"), "<p><img src=\"../../tree/abc/docs/img/ball.png\" alt=\"ball\" title=\"Ball\" /></p>\n");
+		assert_eq! (render ("<alice@example.com>"), "<p><a href=\"mailto:alice@example.com\">alice@example.com</a></p>\n");
+		assert! (render ("| a | b |\n|---|---|\n| 1 | 2 |").contains ("<table>"));
+	}
+}
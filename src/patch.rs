@@ -0,0 +1,318 @@
+use std::path::Path;
+
+use git2::{
+	Oid,
+	Repository,
+	Signature,
+};
+
+use crate::error::Error;
+
+/// Who committed patches on the player's behalf. The author is kept.
+const COMMITTER_NAME: &str = "codepong";
+const COMMITTER_EMAIL: &str = "codepong@localhost";
+
+/// Applies a `git format-patch` series on top of `HEAD` and returns the
+/// last commit. Nothing is checked out and no refs are moved, so the
+/// caller can check it like a fetched commit before resetting to it.
+pub fn apply (repo_path: &Path, series: &str) -> Result <Oid, Error> {
+	let patches = split (series)
+	.into_iter ()
+	.map (parse)
+	.collect::<Result <Vec <_>, _>> ()?;
+	
+	if patches.is_empty () {
+		return Err (Error::bad_request ("That isn't `git format-patch` output"));
+	}
+	
+	let repo = Repository::open (repo_path)?;
+	let committer = Signature::now (COMMITTER_NAME, COMMITTER_EMAIL)?;
+	let mut parent = repo.head ()?.peel_to_commit ()?;
+	
+	for (i, patch) in patches.iter ().enumerate () {
+		let which = || format! ("Patch {} of {} (\"{}\")", i + 1, patches.len (), patch.subject);
+		
+		let diff = git2::Diff::from_buffer (patch.diff.as_bytes ())
+		.map_err (|e| Error::bad_request (format! ("{} has a broken diff: {}", which (), e.message ())))?;
+		
+		// Applied to a tree, so it's an in-memory index and the working
+		// directory is never touched
+		let mut index = repo.apply_to_tree (&parent.tree ()?, &diff, None)
+		.map_err (|e| Error::bad_request (format! ("{} doesn't apply cleanly: {}", which (), e.message ())))?;
+		let tree = repo.find_tree (index.write_tree_to (&repo)?)?;
+		
+		let author = Signature::new (&patch.author_name, &patch.author_email, &patch.time)
+		.map_err (|_| Error::bad_request (format! ("{} has a bad author", which ())))?;
+		let oid = repo.commit (None, &author, &committer, &patch.message, &tree, &[&parent])?;
+		parent = repo.find_commit (oid)?;
+	}
+	
+	Ok (parent.id ())
+}
+
+struct Patch <'a> {
+	author_name: String,
+	author_email: String,
+	time: git2::Time,
+	subject: String,
+	message: String,
+	diff: &'a str,
+}
+
+/// Splits a series at the `From <hash> Mon Sep 17 00:00:00 2001` lines
+/// that `format-patch` starts every patch with
+fn split (series: &str) -> Vec <&str> {
+	let mut starts = vec! [];
+	let mut offset = 0;
+	for line in series.split_inclusive ('\n') {
+		if is_mbox_separator (line) {
+			starts.push (offset);
+		}
+		offset += line.len ();
+	}
+	
+	starts.iter ()
+	.enumerate ()
+	.map (|(i, start)| &series [*start..starts.get (i + 1).copied ().unwrap_or (series.len ())])
+	.collect ()
+}
+
+fn is_mbox_separator (line: &str) -> bool {
+	let hash = match line.strip_prefix ("From ").and_then (|s| s.split (' ').next ()) {
+		Some (x) => x,
+		None => return false,
+	};
+	hash.len () == 40 && hash.bytes ().all (|b| b.is_ascii_hexdigit ())
+}
+
+fn parse (text: &str) -> Result <Patch <'_>, Error> {
+	let bad = |msg: &str| Error::bad_request (format! ("That isn't `git format-patch` output: {}", msg));
+	
+	let (headers, body) = text.split_once ("\n\n").ok_or_else (|| bad ("no headers"))?;
+	
+	let mut from = None;
+	let mut date = None;
+	let mut subject = None;
+	
+	// Skip the mbox separator, and unfold long headers
+	for header in unfold (headers.split_once ('\n').map (|(_, h)| h).unwrap_or ("")) {
+		let (name, value) = match header.split_once (':') {
+			Some (x) => x,
+			None => continue,
+		};
+		let value = decode_words (value.trim ());
+		match name.to_ascii_lowercase ().as_str () {
+			"from" => from = Some (value),
+			"date" => date = Some (value),
+			"subject" => subject = Some (value),
+			_ => (),
+		}
+	}
+	
+	let from = from.ok_or_else (|| bad ("no From header"))?;
+	let (author_name, author_email) = match from.rsplit_once ('<') {
+		Some ((name, email)) => (name.trim ().trim_matches ('"').to_string (), email.trim_end_matches ('>').to_string ()),
+		None => (from.clone (), from.clone ()),
+	};
+	
+	let date = date.ok_or_else (|| bad ("no Date header"))?;
+	let date = chrono::DateTime::parse_from_rfc2822 (&date).map_err (|_| bad ("bad Date header"))?;
+	let time = git2::Time::new (date.timestamp (), date.offset ().local_minus_utc () / 60);
+	
+	let subject = strip_patch_prefix (&subject.ok_or_else (|| bad ("no Subject header"))?).to_string ();
+	
+	let diff_start = if body.starts_with ("diff --git ") {
+		0
+	}
+	else {
+		body.find ("\ndiff --git ").map (|i| i + 1).ok_or_else (|| bad ("no diff"))?
+	};
+	
+	// The log message ends at the last `---` before the diffstat, which
+	// lets messages have `---` lines of their own
+	let notes = &body [..diff_start];
+	let log = notes.rfind ("\n---\n").map (|i| &notes [..i])
+	.or_else (|| notes.strip_prefix ("---\n").map (|_| ""))
+	.unwrap_or (notes)
+	.trim ();
+	
+	let message = match log.is_empty () {
+		true => format! ("{}\n", subject),
+		false => format! ("{}\n\n{}\n", subject, log),
+	};
+	
+	Ok (Patch {
+		author_name,
+		author_email,
+		time,
+		subject,
+		message,
+		diff: strip_signature (&body [diff_start..]),
+	})
+}
+
+fn unfold (headers: &str) -> Vec <String> {
+	let mut out: Vec <String> = vec! [];
+	for line in headers.lines () {
+		match (line.starts_with (' ') || line.starts_with ('\t'), out.last_mut ()) {
+			(true, Some (last)) => last.push_str (line),
+			_ => out.push (line.to_string ()),
+		}
+	}
+	out
+}
+
+/// `[PATCH]`, `[PATCH 2/3]`, `[PATCH v2]`, and so on
+fn strip_patch_prefix (subject: &str) -> &str {
+	match subject.strip_prefix ('[').and_then (|s| s.split_once (']')) {
+		Some ((tag, rest)) if tag.contains ("PATCH") => rest.trim_start (),
+		_ => subject,
+	}
+}
+
+/// Git puts `-- ` and its version after the last patch
+fn strip_signature (diff: &str) -> &str {
+	match diff.rfind ("\n-- \n") {
+		Some (i) if diff [i + 5..].trim ().lines ().count () <= 1 => &diff [..i + 1],
+		_ => diff,
+	}
+}
+
+/// Decodes RFC 2047 encoded words like `=?UTF-8?q?Zo=C3=AB?=`, which Git
+/// uses for names and subjects that aren't plain ASCII. Git only writes
+/// the Q encoding, so other encodings are left alone.
+fn decode_words (value: &str) -> String {
+	let mut out = String::new ();
+	let mut rest = value;
+	let mut after_word = false;
+	
+	while let Some (start) = rest.find ("=?") {
+		let decoded = rest [start + 2..].splitn (3, '?').collect::<Vec <_>> ();
+		let (charset, encoding, tail) = match decoded [..] {
+			[charset, encoding, tail] => (charset, encoding, tail),
+			_ => break,
+		};
+		let (text, after) = match tail.split_once ("?=") {
+			Some (x) => x,
+			None => break,
+		};
+		
+		let between = &rest [..start];
+		if ! (after_word && between.trim ().is_empty ()) {
+			out.push_str (between);
+		}
+		
+		let word = match (charset.eq_ignore_ascii_case ("utf-8"), encoding.eq_ignore_ascii_case ("q")) {
+			(true, true) => decode_q (text),
+			_ => None,
+		};
+		match word {
+			Some (word) => out.push_str (&word),
+			None => out.push_str (&rest [start..rest.len () - after.len ()]),
+		}
+		
+		after_word = true;
+		rest = after;
+	}
+	
+	out.push_str (rest);
+	out
+}
+
+fn decode_q (text: &str) -> Option <String> {
+	let mut bytes = vec! [];
+	let mut iter = text.bytes ();
+	while let Some (b) = iter.next () {
+		match b {
+			b'_' => bytes.push (b' '),
+			b'=' => {
+				let hi = (iter.next ()? as char).to_digit (16)?;
+				let lo = (iter.next ()? as char).to_digit (16)?;
+				bytes.push ((hi * 16 + lo) as u8);
+			},
+			b => bytes.push (b),
+		}
+	}
+	String::from_utf8 (bytes).ok ()
+}
+
+#[cfg (test)]
+mod tests {
+	use std::process::Command;
+	
+	use super::*;
+	
+	fn git (dir: &Path, args: &[&str]) -> String {
+		let output = Command::new ("git")
+		.args (args)
+		.current_dir (dir)
+		.env ("GIT_AUTHOR_NAME", "Zoë Ünïcode")
+		.env ("GIT_AUTHOR_EMAIL", "zoe@example.com")
+		.env ("GIT_COMMITTER_NAME", "alice")
+		.env ("GIT_COMMITTER_EMAIL", "alice@example.com")
+		.output ()
+		.unwrap ();
+		assert! (output.status.success (), "git {:?}", args);
+		String::from_utf8 (output.stdout).unwrap ()
+	}
+	
+	#[test]
+	fn applying () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let server = dir.path ().join ("server");
+		let player = dir.path ().join ("player");
+		
+		std::fs::create_dir (&server).unwrap ();
+		git (&server, &["init", "-q", "-b", "main"]);
+		std::fs::write (server.join ("game.html"), "<p>Pong</p>\n").unwrap ();
+		git (&server, &["add", "."]);
+		git (&server, &["commit", "-q", "-m", "Start"]);
+		
+		git (dir.path (), &["clone", "-q", server.to_str ().unwrap (), "player"]);
+		std::fs::write (player.join ("game.html"), "<p>Pong, but faster</p>\n").unwrap ();
+		git (&player, &["commit", "-q", "-am", "Faster, with a subject that's long enough to get folded by format-patch", "-m", "Why:\n\n---\nIt was slow"]);
+		std::fs::write (player.join ("ball.js"), "let speed = 2;\n").unwrap ();
+		git (&player, &["add", "."]);
+		git (&player, &["commit", "-q", "-m", "Ball"]);
+		
+		let series = git (&player, &["format-patch", "--stdout", "origin/main..main"]);
+		assert_eq! (split (&series).len (), 2);
+		
+		let oid = apply (&server, &series).unwrap ();
+		
+		let repo = Repository::open (&server).unwrap ();
+		let ours = repo.find_commit (oid).unwrap ();
+		let player_repo = Repository::open (&player).unwrap ();
+		let theirs = player_repo.head ().unwrap ().peel_to_commit ().unwrap ();
+		assert_eq! (ours.tree_id (), theirs.tree_id ());
+		assert_eq! (ours.author ().name (), Some ("Zoë Ünïcode"));
+		assert_eq! (ours.author ().when ().seconds (), theirs.author ().when ().seconds ());
+		assert_eq! (ours.message (), Some ("Ball\n"));
+		
+		let first = ours.parent (0).unwrap ();
+		assert_eq! (first.message (), Some ("Faster, with a subject that's long enough to get folded by format-patch\n\nWhy:\n\n---\nIt was slow\n"));
+		assert_eq! (first.parent_id (0).unwrap (), repo.head ().unwrap ().target ().unwrap ());
+		
+		// Nothing moved until the caller says so
+		assert_ne! (repo.head ().unwrap ().target (), Some (oid));
+		
+		// Conflicts with `main` are refused
+		std::fs::write (server.join ("game.html"), "<p>Pong, but slower</p>\n").unwrap ();
+		git (&server, &["commit", "-q", "-am", "Slower"]);
+		assert! (apply (&server, &series).is_err ());
+		
+		for garbage in ["", "hello", "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\nFrom: a <b>\n\nno diff"] {
+			assert! (apply (&server, garbage).is_err (), "{}", garbage);
+		}
+	}
+	
+	#[test]
+	fn encoded_words () {
+		assert_eq! (decode_words ("=?UTF-8?q?Zo=C3=AB=20=C3=9Cn=C3=AFcode?= <z@y>"), "Zoë Ünïcode <z@y>");
+		assert_eq! (decode_words ("[PATCH] =?UTF-8?q?=C3=84nd?= =?UTF-8?q?erung_1?="), "[PATCH] Änderung 1");
+		assert_eq! (decode_words ("plain"), "plain");
+		assert_eq! (decode_words ("=?ISO-8859-1?b?abc?= x"), "=?ISO-8859-1?b?abc?= x");
+		assert_eq! (strip_patch_prefix ("[PATCH v2 3/4] Faster"), "Faster");
+		assert_eq! (strip_patch_prefix ("[wip] Faster"), "[wip] Faster");
+	}
+}
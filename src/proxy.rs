@@ -0,0 +1,250 @@
+use std::{
+	convert::TryFrom,
+	net::{IpAddr, SocketAddr},
+};
+
+use hyper::{
+	HeaderMap,
+	Uri,
+};
+use serde::Deserialize;
+
+use crate::config;
+
+/// Where users reach codepong from the outside, like
+/// `https://example.com/codepong/`. Always ends with a `/`.
+#[derive (Clone, Debug, Deserialize, PartialEq)]
+#[serde (try_from = "String")]
+pub struct PublicUrl {
+	url: String,
+	path: String,
+}
+
+impl TryFrom <String> for PublicUrl {
+	type Error = String;
+	
+	fn try_from (s: String) -> Result <Self, Self::Error> {
+		let uri: Uri = s.parse ().map_err (|_| format! ("`{}` isn't a URL", s))?;
+		
+		match uri.scheme_str () {
+			Some ("http") | Some ("https") => (),
+			_ => return Err (format! ("`{}` must start with http:// or https://", s)),
+		}
+		if uri.authority ().is_none () || uri.query ().is_some () {
+			return Err (format! ("`{}` must have a host and no query", s));
+		}
+		
+		let mut path = uri.path ().to_string ();
+		if ! path.ends_with ('/') {
+			path.push ('/');
+		}
+		
+		Ok (Self {
+			url: format! ("{}://{}{}", uri.scheme_str ().unwrap_or ("https"), uri.authority ().map (|a| a.as_str ()).unwrap_or (""), path),
+			path,
+		})
+	}
+}
+
+impl PublicUrl {
+	pub fn as_str (&self) -> &str {
+		&self.url
+	}
+}
+
+/// The client behind a request, after looking through trusted proxies
+#[derive (Clone, Copy, Debug)]
+pub struct Client {
+	pub ip: IpAddr,
+	
+	/// True if the client's own connection was HTTPS, even if the proxy
+	/// talks plain HTTP to us
+	pub https: bool,
+}
+
+/// Knows how codepong is mounted behind reverse proxies, and which
+/// proxies to believe about the real client
+pub struct Proxy {
+	public_url: Option <PublicUrl>,
+	trusted: Vec <IpAddr>,
+}
+
+impl Proxy {
+	pub fn new (config: &config::Http) -> Self {
+		Self {
+			public_url: config.public_url.clone (),
+			trusted: config.trusted_proxies.clone (),
+		}
+	}
+	
+	/// Path prefix for absolute links and redirects, `/` if codepong has
+	/// the whole host to itself
+	pub fn base_path (&self) -> &str {
+		self.public_url.as_ref ().map (|u| u.path.as_str ()).unwrap_or ("/")
+	}
+	
+	/// Strips the base path off a request path, for proxies that pass
+	/// it through instead of cutting it off themselves
+	pub fn strip_base <'a> (&self, path: &'a str) -> &'a str {
+		let base = self.base_path ();
+		if base == "/" {
+			return path;
+		}
+		
+		if path == base.trim_end_matches ('/') {
+			return "/";
+		}
+		
+		match path.strip_prefix (base) {
+			// Keep the leading slash
+			Some (_) => &path [base.len () - 1..],
+			None => path,
+		}
+	}
+	
+	/// The configured public URL, or a guess from the request
+	pub fn public_url (&self, headers: &HeaderMap, client: Client) -> Option <String> {
+		if let Some (url) = &self.public_url {
+			return Some (url.url.clone ());
+		}
+		
+		self.origin (headers, client).map (|origin| format! ("{}/", origin))
+	}
+	
+	/// Scheme and host that browsers see, like `https://example.com`, to
+	/// compare with `Origin` headers
+	pub fn origin (&self, headers: &HeaderMap, client: Client) -> Option <String> {
+		if let Some (url) = &self.public_url {
+			let origin_len = url.url.len () - url.path.len ();
+			return Some (url.url [..origin_len].to_string ());
+		}
+		
+		let host = headers.get ("host")?.to_str ().ok ()?;
+		let valid = ! host.is_empty () && host.chars ().all (|c| c.is_ascii_alphanumeric () || "-.[]:".contains (c));
+		if ! valid {
+			return None;
+		}
+		
+		let scheme = if client.https { "https" } else { "http" };
+		Some (format! ("{}://{}", scheme, host))
+	}
+	
+	/// Works out the real client. `X-Forwarded-*` headers are only
+	/// believed when they come from a trusted proxy, otherwise anyone
+	/// could dodge rate limits by making up an address.
+	pub fn client (&self, headers: &HeaderMap, remote: SocketAddr, tls: bool) -> Client {
+		let remote_ip = canonical (remote.ip ());
+		
+		if ! self.trusted.contains (&remote_ip) {
+			return Client {
+				ip: remote_ip,
+				https: tls,
+			};
+		}
+		
+		// Each proxy appends the address it got the request from, so walk
+		// from the right until we find one that isn't ours
+		let forwarded: Vec <&str> = headers.get_all ("x-forwarded-for").iter ()
+		.filter_map (|v| v.to_str ().ok ())
+		.flat_map (|v| v.split (','))
+		.map (|s| s.trim ())
+		.collect ();
+		
+		let mut ip = remote_ip;
+		for s in forwarded.iter ().rev () {
+			ip = match s.parse::<IpAddr> () {
+				Ok (x) => canonical (x),
+				Err (_) => break,
+			};
+			if ! self.trusted.contains (&ip) {
+				break;
+			}
+		}
+		
+		let https = match headers.get ("x-forwarded-proto").and_then (|v| v.to_str ().ok ()) {
+			Some (proto) => proto.split (',').next ().unwrap_or ("").trim ().eq_ignore_ascii_case ("https"),
+			None => tls,
+		};
+		
+		Client {
+			ip,
+			https,
+		}
+	}
+}
+
+/// IPv4 clients on a dual-stack socket show up as `::ffff:1.2.3.4`
+fn canonical (ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6 (v6) => v6.to_ipv4_mapped ().map (IpAddr::V4).unwrap_or (ip),
+		x => x,
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	fn proxy (public_url: Option <&str>) -> Proxy {
+		Proxy {
+			public_url: public_url.map (|s| PublicUrl::try_from (s.to_string ()).unwrap ()),
+			trusted: vec! ["127.0.0.1".parse ().unwrap (), "10.0.0.2".parse ().unwrap ()],
+		}
+	}
+	
+	#[test]
+	fn base_path () {
+		let p = proxy (Some ("https://example.com/codepong"));
+		assert_eq! (p.base_path (), "/codepong/");
+		let client = Client {
+			ip: "1.2.3.4".parse ().unwrap (),
+			https: false,
+		};
+		assert_eq! (p.public_url (&HeaderMap::new (), client).as_deref (), Some ("https://example.com/codepong/"));
+		assert_eq! (p.origin (&HeaderMap::new (), client).as_deref (), Some ("https://example.com"));
+		
+		for (path, expected) in [
+			("/codepong/home", "/home"),
+			("/codepong/", "/"),
+			("/codepong", "/"),
+			("/home", "/home"),
+			("/codepongs/home", "/codepongs/home"),
+		] {
+			assert_eq! (p.strip_base (path), expected, "{}", path);
+		}
+		
+		let p = proxy (None);
+		assert_eq! (p.base_path (), "/");
+		assert_eq! (p.strip_base ("/home"), "/home");
+		
+		for bad in ["example.com/codepong", "ftp://example.com/", "https:///x", "https://example.com/?a=b"] {
+			assert! (PublicUrl::try_from (bad.to_string ()).is_err (), "{}", bad);
+		}
+	}
+	
+	#[test]
+	fn forwarded () {
+		let p = proxy (None);
+		let mut headers = HeaderMap::new ();
+		headers.insert ("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2".parse ().unwrap ());
+		headers.insert ("x-forwarded-proto", "https".parse ().unwrap ());
+		
+		let from_proxy: SocketAddr = "127.0.0.1:5000".parse ().unwrap ();
+		let c = p.client (&headers, from_proxy, false);
+		assert_eq! (c.ip, "1.2.3.4".parse::<IpAddr> ().unwrap ());
+		assert! (c.https);
+		
+		let mapped: SocketAddr = "[::ffff:127.0.0.1]:5000".parse ().unwrap ();
+		assert_eq! (p.client (&headers, mapped, false).ip, "1.2.3.4".parse::<IpAddr> ().unwrap ());
+		
+		// Strangers can't pretend to be someone else
+		let direct: SocketAddr = "5.5.5.5:5000".parse ().unwrap ();
+		let c = p.client (&headers, direct, false);
+		assert_eq! (c.ip, "5.5.5.5".parse::<IpAddr> ().unwrap ());
+		assert! (! c.https);
+		
+		// Garbage stops the walk at the last good address
+		headers.insert ("x-forwarded-for", "1.2.3.4, junk".parse ().unwrap ());
+		assert_eq! (p.client (&headers, from_proxy, false).ip, "127.0.0.1".parse::<IpAddr> ().unwrap ());
+	}
+}
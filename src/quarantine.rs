@@ -0,0 +1,229 @@
+use std::{
+	fs,
+	io,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, SystemTime},
+};
+
+use git2::Repository;
+
+use crate::error::Error;
+
+/// Quarantines nobody cleaned up, because of a crash or a restart, are
+/// deleted after this long
+pub const MAX_AGE: Duration = Duration::from_secs (3_600);
+
+/// Where objects from a player wait until their commit is accepted.
+///
+/// Each attempt gets a bare repo of its own, next to the real one, that
+/// borrows the real repo's objects through `objects/info/alternates`.
+/// Fetches only download what's new, and objects from rejected attempts
+/// never reach the real repo. Dropping a quarantine deletes it.
+pub struct Quarantine {
+	path: PathBuf,
+	objects: PathBuf,
+}
+
+impl Quarantine {
+	/// Starts a quarantine where `branch` and `HEAD` are where `branch` is
+	/// in the real repo
+	pub fn new (repo_path: &Path, branch: &str) -> Result <Self, Error> {
+		static COUNT: AtomicU64 = AtomicU64::new (0);
+		
+		let real = Repository::open (repo_path)?;
+		let objects = fs::canonicalize (real.path ().join ("objects"))?;
+		let refname = format! ("refs/heads/{}", branch);
+		let tip = real.refname_to_id (&refname)?;
+		
+		let secs = SystemTime::now ().duration_since (SystemTime::UNIX_EPOCH).unwrap_or_default ().as_secs ();
+		let path = dir (repo_path).join (format! ("{}-{}-{}", secs, std::process::id (), COUNT.fetch_add (1, Ordering::Relaxed)));
+		
+		let quarantine = Self {
+			path,
+			objects,
+		};
+		
+		let repo = Repository::init_bare (&quarantine.path)?;
+		fs::write (quarantine.path.join ("objects/info/alternates"), format! ("{}\n", quarantine.objects.display ()))?;
+		repo.reference (&refname, tip, true, "codepong: quarantine")?;
+		repo.set_head (&refname)?;
+		
+		Ok (quarantine)
+	}
+	
+	pub fn path (&self) -> &Path {
+		&self.path
+	}
+	
+	/// Moves the new objects into the real repo. Packs go before their
+	/// indexes, so nobody sees an index for a pack that isn't there yet.
+	pub fn accept (self) -> Result <(), Error> {
+		for entry in fs::read_dir (self.path.join ("objects"))? {
+			let entry = entry?;
+			let name = entry.file_name ();
+			let name = match name.to_str () {
+				Some (x) => x,
+				None => continue,
+			};
+			
+			if name == "pack" {
+				let mut files = fs::read_dir (entry.path ())?
+				.map (|e| e.map (|e| e.path ()))
+				.collect::<Result <Vec <_>, _>> ()?;
+				files.sort_by_key (|f| f.extension ().map (|x| x == "idx").unwrap_or (false));
+				
+				for file in files {
+					move_file (&file, &self.objects.join ("pack").join (file.file_name ().unwrap_or_default ()))?;
+				}
+			}
+			else if name.len () == 2 && name.bytes ().all (|b| b.is_ascii_hexdigit ()) {
+				let target = self.objects.join (name);
+				fs::create_dir_all (&target)?;
+				
+				for file in fs::read_dir (entry.path ())? {
+					let file = file?.path ();
+					move_file (&file, &target.join (file.file_name ().unwrap_or_default ()))?;
+				}
+			}
+		}
+		
+		Ok (())
+	}
+}
+
+impl Drop for Quarantine {
+	fn drop (&mut self) {
+		let path = std::mem::take (&mut self.path);
+		let remove = move || {
+			if let Err (e) = fs::remove_dir_all (&path) {
+				tracing::warn! ("Couldn't delete quarantine {}: {}", path.display (), e);
+			}
+		};
+		
+		// Handlers drop them on the runtime's threads, which shouldn't
+		// wait on the disk
+		match tokio::runtime::Handle::try_current () {
+			Ok (runtime) => drop (runtime.spawn_blocking (remove)),
+			Err (_) => remove (),
+		}
+	}
+}
+
+/// Deletes quarantines that were abandoned longer than `max_age` ago
+pub fn cleanup (repo_path: &Path, max_age: Duration) -> io::Result <()> {
+	let entries = match fs::read_dir (dir (repo_path)) {
+		Err (e) if e.kind () == io::ErrorKind::NotFound => return Ok (()),
+		x => x?,
+	};
+	
+	for entry in entries {
+		let entry = entry?;
+		let age = entry.metadata ()?.modified ()?.elapsed ().unwrap_or_default ();
+		if age >= max_age {
+			tracing::info! ("Deleting abandoned quarantine {}", entry.path ().display ());
+			fs::remove_dir_all (entry.path ())?;
+		}
+	}
+	
+	Ok (())
+}
+
+/// Next to the real repo, so moving objects out is just a rename, but
+/// outside of what `/git/` serves
+fn dir (repo_path: &Path) -> PathBuf {
+	repo_path.with_file_name ("quarantine")
+}
+
+fn move_file (from: &Path, to: &Path) -> io::Result <()> {
+	// Objects are named after their contents, so one that's already
+	// there is the same
+	if to.exists () || fs::rename (from, to).is_ok () {
+		return Ok (());
+	}
+	
+	// Renaming fails across filesystems. A copy is written under another
+	// name first, so nobody ever sees half a pack under the real one.
+	let mut temp = to.as_os_str ().to_owned ();
+	temp.push (format! (".tmp-{}", std::process::id ()));
+	let temp = PathBuf::from (temp);
+	
+	let copied = fs::copy (from, &temp).and_then (|_| fs::rename (&temp, to));
+	if copied.is_err () {
+		fs::remove_file (&temp).ok ();
+	}
+	copied
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn quarantining () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let sig = git2::Signature::now ("alice", "alice@example.com").unwrap ();
+		let real_path = dir.path ().join ("repo");
+		let real = Repository::init (&real_path).unwrap ();
+		
+		let commit = |repo: &Repository, content: &[u8], parents: &[&git2::Commit]| {
+			let blob = repo.blob (content).unwrap ();
+			let mut tree = repo.treebuilder (None).unwrap ();
+			tree.insert ("game.html", blob, 0o100644).unwrap ();
+			let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+			repo.commit (None, &sig, &sig, "Turn", &tree, parents).unwrap ()
+		};
+		
+		let start = commit (&real, b"<p>Pong</p>", &[]);
+		real.reference ("refs/heads/main", start, true, "").unwrap ();
+		real.set_head ("refs/heads/main").unwrap ();
+		
+		// Rejected
+		let q = Quarantine::new (&real_path, "main").unwrap ();
+		let q_path = q.path ().to_path_buf ();
+		let rejected = {
+			let repo = Repository::open (q.path ()).unwrap ();
+			let parent = repo.head ().unwrap ().peel_to_commit ().unwrap ();
+			commit (&repo, b"<p>Nope</p>", &[&parent])
+		};
+		drop (q);
+		assert! (! q_path.exists ());
+		assert! (real.find_commit (rejected).is_err ());
+		
+		// Accepted, with a pack and loose objects
+		let q = Quarantine::new (&real_path, "main").unwrap ();
+		let accepted = {
+			let repo = Repository::open (q.path ()).unwrap ();
+			let parent = repo.head ().unwrap ().peel_to_commit ().unwrap ();
+			let packed = commit (&repo, b"<p>Pong, but faster</p>", &[&parent]);
+			
+			let mut builder = repo.packbuilder ().unwrap ();
+			builder.insert_commit (packed).unwrap ();
+			let mut buf = git2::Buf::new ();
+			builder.write_buf (&mut buf).unwrap ();
+			let odb = repo.odb ().unwrap ();
+			let mut writer = odb.packwriter ().unwrap ();
+			io::Write::write_all (&mut writer, &buf).unwrap ();
+			writer.commit ().unwrap ();
+			
+			let packed = repo.find_commit (packed).unwrap ();
+			commit (&repo, b"<p>Pong, but fastest</p>", &[&packed])
+		};
+		assert! (real.find_commit (accepted).is_err ());
+		q.accept ().unwrap ();
+		
+		let real = Repository::open (&real_path).unwrap ();
+		let accepted = real.find_commit (accepted).unwrap ();
+		assert! (real.find_commit (accepted.parent_id (0).unwrap ()).is_ok ());
+		assert! (fs::read_dir (real_path.join (".git/objects/pack")).unwrap ().count () >= 2);
+		
+		// Abandoned
+		let q = Quarantine::new (&real_path, "main").unwrap ();
+		let q_path = q.path ().to_path_buf ();
+		std::mem::forget (q);
+		cleanup (&real_path, MAX_AGE).unwrap ();
+		assert! (q_path.exists ());
+		cleanup (&real_path, Duration::from_secs (0)).unwrap ();
+		assert! (! q_path.exists ());
+	}
+}
@@ -0,0 +1,210 @@
+use std::io::SeekFrom;
+
+use hyper::{
+	Body,
+	HeaderMap,
+	Response,
+	StatusCode,
+	http::response::Builder,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// What a `Range` header asks for, once checked against the length of
+/// the content
+#[derive (Debug, PartialEq)]
+pub enum Requested {
+	/// No usable `Range`, send everything
+	Full,
+	
+	/// Start and end offsets, both inclusive
+	Partial (u64, u64),
+	
+	Unsatisfiable,
+}
+
+/// Parses a `Range` header value for content that's `len` bytes long.
+/// Only single byte ranges are supported, anything else is ignored and
+/// gets the full content, which RFC 7233 allows.
+pub fn parse (value: &str, len: u64) -> Requested {
+	use Requested::*;
+	
+	let spec = match value.trim ().strip_prefix ("bytes=") {
+		Some (x) if ! x.contains (',') => x.trim (),
+		_ => return Full,
+	};
+	
+	let (start, end) = match spec.split_once ('-') {
+		Some (x) => x,
+		None => return Full,
+	};
+	
+	match (start.parse::<u64> ().ok (), end.parse::<u64> ().ok ()) {
+		// bytes=-500 is the last 500 bytes
+		(None, Some (suffix)) if start.is_empty () => {
+			if suffix == 0 || len == 0 {
+				Unsatisfiable
+			}
+			else {
+				Partial (len.saturating_sub (suffix), len - 1)
+			}
+		},
+		(Some (start), None) if end.is_empty () => {
+			if start >= len {
+				Unsatisfiable
+			}
+			else {
+				Partial (start, len - 1)
+			}
+		},
+		(Some (start), Some (end)) if start <= end => {
+			if start >= len {
+				Unsatisfiable
+			}
+			else {
+				Partial (start, end.min (len - 1))
+			}
+		},
+		_ => Full,
+	}
+}
+
+/// Where the bytes of a file response come from
+pub enum Source {
+	/// Already in memory, like a Git blob
+	Memory (Vec <u8>),
+	
+	/// Streamed from disk, so large files don't have to fit in RAM
+	File (tokio::fs::File),
+}
+
+/// Finishes a response for file-like content, honoring `Range` and
+/// `If-Range`. `builder` should already have the content type and
+/// caching headers.
+pub async fn respond (
+	req_headers: &HeaderMap,
+	etag: Option <&str>,
+	builder: Builder,
+	source: Source,
+) -> anyhow::Result <Response <Body>>
+{
+	let len = match &source {
+		Source::Memory (bytes) => bytes.len () as u64,
+		Source::File (file) => file.metadata ().await?.len (),
+	};
+	
+	// A stale If-Range means the client's partial copy is of some other
+	// version, so it needs the whole thing
+	let if_range_ok = match req_headers.get ("if-range").and_then (|v| v.to_str ().ok ()) {
+		None => true,
+		Some (v) => Some (v) == etag,
+	};
+	
+	let requested = match req_headers.get ("range").and_then (|v| v.to_str ().ok ()) {
+		Some (v) if if_range_ok => parse (v, len),
+		_ => Requested::Full,
+	};
+	
+	let builder = builder.header ("accept-ranges", "bytes");
+	
+	let (builder, start, count) = match requested {
+		Requested::Full => (builder, 0, len),
+		Requested::Partial (start, end) => (
+			builder
+			.status (StatusCode::PARTIAL_CONTENT)
+			.header ("content-range", format! ("bytes {}-{}/{}", start, end, len)),
+			start,
+			end - start + 1,
+		),
+		Requested::Unsatisfiable => return Ok (builder
+			.status (StatusCode::RANGE_NOT_SATISFIABLE)
+			.header ("content-range", format! ("bytes */{}", len))
+			.body (Body::empty ())?),
+	};
+	
+	let body = match source {
+		Source::Memory (mut bytes) => {
+			bytes.truncate ((start + count) as usize);
+			bytes.drain (..start as usize);
+			Body::from (bytes)
+		},
+		Source::File (mut file) => {
+			file.seek (SeekFrom::Start (start)).await?;
+			Body::wrap_stream (tokio_util::io::ReaderStream::new (file.take (count)))
+		},
+	};
+	
+	Ok (builder
+	.header ("content-length", count)
+	.body (body)?)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	use super::Requested::*;
+	
+	#[test]
+	fn parsing () {
+		for (value, expected) in vec! [
+			("bytes=0-99", Partial (0, 99)),
+			("bytes=100-", Partial (100, 999)),
+			("bytes=-100", Partial (900, 999)),
+			("bytes=-5000", Partial (0, 999)),
+			("bytes=500-5000", Partial (500, 999)),
+			("bytes=999-999", Partial (999, 999)),
+			("bytes=1000-", Unsatisfiable),
+			("bytes=1000-1001", Unsatisfiable),
+			("bytes=-0", Unsatisfiable),
+			("bytes=0-1,5-6", Full),
+			("bytes=5-1", Full),
+			("bytes=abc", Full),
+			("bytes=-", Full),
+			("lines=0-1", Full),
+		].into_iter () {
+			assert_eq! (parse (value, 1_000), expected, "{}", value);
+		}
+		
+		assert_eq! (parse ("bytes=0-", 0), Unsatisfiable);
+		assert_eq! (parse ("bytes=-1", 0), Unsatisfiable);
+	}
+	
+	#[tokio::test]
+	async fn ranges () {
+		use futures_util::StreamExt;
+		
+		async fn body_bytes (resp: Response <Body>) -> Vec <u8> {
+			let mut body = resp.into_body ();
+			let mut v = vec! [];
+			while let Some (chunk) = body.next ().await {
+				v.extend_from_slice (&chunk.unwrap ());
+			}
+			v
+		}
+		
+		let content: Vec <u8> = (0..=255).collect ();
+		let dir = tempfile::tempdir ().unwrap ();
+		let path = dir.path ().join ("sfx.ogg");
+		std::fs::write (&path, &content).unwrap ();
+		
+		let mut headers = HeaderMap::new ();
+		headers.insert ("range", "bytes=10-19".parse ().unwrap ());
+		
+		for source in [
+			Source::Memory (content.clone ()),
+			Source::File (tokio::fs::File::open (&path).await.unwrap ()),
+		] {
+			let resp = respond (&headers, Some ("\"x\""), Response::builder (), source).await.unwrap ();
+			assert_eq! (resp.status (), StatusCode::PARTIAL_CONTENT);
+			assert_eq! (resp.headers () ["content-range"], "bytes 10-19/256");
+			assert_eq! (resp.headers () ["content-length"], "10");
+			assert_eq! (body_bytes (resp).await, &content [10..20]);
+		}
+		
+		// Stale If-Range gets the whole file
+		headers.insert ("if-range", "\"old\"".parse ().unwrap ());
+		let file = tokio::fs::File::open (&path).await.unwrap ();
+		let resp = respond (&headers, Some ("\"x\""), Response::builder (), Source::File (file)).await.unwrap ();
+		assert_eq! (resp.status (), StatusCode::OK);
+		assert_eq! (body_bytes (resp).await, content);
+	}
+}
@@ -0,0 +1,156 @@
+use std::{
+	collections::HashMap,
+	fmt::Write,
+	sync::{
+		Mutex,
+		atomic::{AtomicU64, Ordering},
+	},
+};
+
+use tokio::time::Instant;
+
+use crate::{
+	config,
+	error::Error,
+	token_bucket::TokenBucket,
+};
+
+/// Past this many clients, buckets that have refilled are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// One kind of limit, like "POSTs per IP", with a bucket for each key
+pub struct Limiter {
+	name: &'static str,
+	limit: config::Limit,
+	buckets: Mutex <HashMap <String, TokenBucket>>,
+	allowed: AtomicU64,
+	limited: AtomicU64,
+}
+
+impl Limiter {
+	fn new (name: &'static str, limit: config::Limit) -> Self {
+		Self {
+			name,
+			limit,
+			buckets: Default::default (),
+			allowed: Default::default (),
+			limited: Default::default (),
+		}
+	}
+	
+	/// Takes a token for `key`, or says how long to wait for one
+	pub fn check (&self, key: &str) -> Result <(), Error> {
+		if self.limit.per_minute == 0 {
+			return Ok (());
+		}
+		
+		let now = Instant::now ();
+		let wait = {
+			let mut buckets = self.buckets.lock ().map_err (|_| anyhow::anyhow! ("Rate limit lock is poisoned"))?;
+			
+			if buckets.len () >= PRUNE_THRESHOLD {
+				buckets.retain (|_, bucket| ! bucket.is_full (now));
+			}
+			
+			let bucket = buckets.entry (key.to_string ())
+			.or_insert_with (|| TokenBucket::new (
+				self.limit.burst.into (),
+				f64::from (self.limit.per_minute) / 60.0,
+				now,
+			));
+			
+			match bucket.try_take (now) {
+				true => None,
+				false => Some (bucket.time_until_token (now)),
+			}
+		};
+		
+		match wait {
+			None => {
+				self.allowed.fetch_add (1, Ordering::Relaxed);
+				Ok (())
+			},
+			Some (wait) => {
+				self.limited.fetch_add (1, Ordering::Relaxed);
+				tracing::info! ("Rate limited {} for {}", key, self.name);
+				
+				// Round up, so clients that obey Retry-After aren't early
+				let secs = wait.as_secs () + u64::from (wait.subsec_nanos () > 0);
+				Err (Error::TooManyRequests (secs.max (1)))
+			},
+		}
+	}
+	
+	fn write_metrics (&self, out: &mut String) -> std::fmt::Result {
+		let tracked = self.buckets.lock ().map (|b| b.len ()).unwrap_or_default ();
+		
+		writeln! (out, "codepong_rate_limit_burst{{limit=\"{}\"}} {}", self.name, self.limit.burst)?;
+		writeln! (out, "codepong_rate_limit_per_minute{{limit=\"{}\"}} {}", self.name, self.limit.per_minute)?;
+		writeln! (out, "codepong_rate_limit_tracked_keys{{limit=\"{}\"}} {}", self.name, tracked)?;
+		writeln! (out, "codepong_rate_limit_allowed_total{{limit=\"{}\"}} {}", self.name, self.allowed.load (Ordering::Relaxed))?;
+		writeln! (out, "codepong_rate_limit_limited_total{{limit=\"{}\"}} {}", self.name, self.limited.load (Ordering::Relaxed))?;
+		Ok (())
+	}
+}
+
+pub struct RateLimits {
+	pub post_per_ip: Limiter,
+	pub post_per_user: Limiter,
+	pub git_per_ip: Limiter,
+}
+
+impl RateLimits {
+	pub fn new (config: &config::RateLimits) -> Self {
+		Self {
+			post_per_ip: Limiter::new ("post_per_ip", config.post_per_ip),
+			post_per_user: Limiter::new ("post_per_user", config.post_per_user),
+			git_per_ip: Limiter::new ("git_per_ip", config.git_per_ip),
+		}
+	}
+	
+	/// Prometheus text format
+	pub fn metrics (&self) -> String {
+		let mut out = String::new ();
+		for limiter in [&self.post_per_ip, &self.post_per_user, &self.git_per_ip] {
+			limiter.write_metrics (&mut out).ok ();
+		}
+		out
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn limiting () {
+		let limiter = Limiter::new ("test", config::Limit {
+			burst: 2,
+			per_minute: 1,
+		});
+		
+		assert! (limiter.check ("1.2.3.4").is_ok ());
+		assert! (limiter.check ("1.2.3.4").is_ok ());
+		match limiter.check ("1.2.3.4") {
+			Err (Error::TooManyRequests (secs)) => assert! (secs > 55 && secs <= 60, "{}", secs),
+			_ => panic! ("Should have been rate limited"),
+		}
+		
+		// Other clients have their own buckets
+		assert! (limiter.check ("5.6.7.8").is_ok ());
+		
+		let mut out = String::new ();
+		limiter.write_metrics (&mut out).unwrap ();
+		assert! (out.contains ("codepong_rate_limit_allowed_total{limit=\"test\"} 3\n"));
+		assert! (out.contains ("codepong_rate_limit_limited_total{limit=\"test\"} 1\n"));
+		assert! (out.contains ("codepong_rate_limit_tracked_keys{limit=\"test\"} 2\n"));
+		
+		let off = Limiter::new ("off", config::Limit {
+			burst: 0,
+			per_minute: 0,
+		});
+		for _ in 0..100 {
+			assert! (off.check ("1.2.3.4").is_ok ());
+		}
+	}
+}
@@ -0,0 +1,201 @@
+use std::{
+	fmt,
+	path::{Component, Path, PathBuf},
+};
+
+use percent_encoding::{
+	AsciiSet,
+	CONTROLS,
+	percent_decode_str,
+	utf8_percent_encode,
+};
+
+/// What can't appear as-is in one segment of a URL path. `%` is in here
+/// so names that look encoded already stay the way they are.
+const SEGMENT: &AsciiSet = &CONTROLS
+.add (b' ').add (b'"').add (b'#').add (b'%').add (b'/').add (b'<').add (b'>')
+.add (b'?').add (b'[').add (b'\\').add (b']').add (b'^').add (b'`').add (b'{')
+.add (b'|').add (b'}');
+
+/// Why a request path was refused. Everything except `NotFound` means the
+/// client sent something hostile or broken.
+#[derive (Debug, PartialEq)]
+pub enum UnsafePath {
+	Malformed,
+	Absolute,
+	Traversal,
+	EscapesRoot,
+	NotFound,
+}
+
+impl fmt::Display for UnsafePath {
+	fn fmt (&self, f: &mut fmt::Formatter <'_>) -> fmt::Result {
+		let s = match self {
+			Self::Malformed => "Path is not valid UTF-8 or contains forbidden characters",
+			Self::Absolute => "Path must be relative",
+			Self::Traversal => "Path must not contain `..`",
+			Self::EscapesRoot => "Path leads outside of its root directory",
+			Self::NotFound => "Path not found",
+		};
+		
+		f.write_str (s)
+	}
+}
+
+/// Percent-decodes the tail of a request URI and turns it into a relative
+/// path made only of normal components. Empty and `.` segments are
+/// dropped, anything that could climb out of a root is rejected.
+pub fn relative_path (tail: &str) -> Result <PathBuf, UnsafePath> {
+	let decoded = percent_decode_str (tail).decode_utf8 ()
+	.map_err (|_| UnsafePath::Malformed)?;
+	
+	if decoded.contains (['\0', '\\']) {
+		return Err (UnsafePath::Malformed);
+	}
+	if decoded.starts_with ('/') {
+		return Err (UnsafePath::Absolute);
+	}
+	
+	let mut path = PathBuf::new ();
+	
+	for segment in decoded.split ('/') {
+		match segment {
+			"" | "." => continue,
+			".." => return Err (UnsafePath::Traversal),
+			_ => (),
+		}
+		
+		// Catches things like Windows drive prefixes
+		let mut components = Path::new (segment).components ();
+		match (components.next (), components.next ()) {
+			(Some (Component::Normal (_)), None) => path.push (segment),
+			_ => return Err (UnsafePath::Malformed),
+		}
+	}
+	
+	Ok (path)
+}
+
+/// Percent-encodes a file or directory name for one segment of a link,
+/// the other way around from `relative_path`
+pub fn url_segment (name: &str) -> String {
+	utf8_percent_encode (name, SEGMENT).to_string ()
+}
+
+/// Resolves a request URI tail to a file or directory under `root`. The
+/// result is canonicalized, so symlinks that point outside of `root` are
+/// caught too.
+pub async fn resolve (root: &Path, tail: &str) -> Result <PathBuf, UnsafePath> {
+	let relative = relative_path (tail)?;
+	
+	let root = tokio::fs::canonicalize (root).await
+	.map_err (|_| UnsafePath::NotFound)?;
+	let full = tokio::fs::canonicalize (root.join (relative)).await
+	.map_err (|_| UnsafePath::NotFound)?;
+	
+	if ! full.starts_with (&root) {
+		return Err (UnsafePath::EscapesRoot);
+	}
+	
+	Ok (full)
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	const HOSTILE: &[&str] = &[
+		"../irc.toml",
+		"../../game/irc.toml",
+		"a/../../irc.toml",
+		"./../irc.toml",
+		"%2e%2e/irc.toml",
+		"%2E%2E%2Firc.toml",
+		"..%2firc.toml",
+		"a%2f..%2f..%2firc.toml",
+		"/etc/passwd",
+		"%2fetc%2fpasswd",
+		"//etc/passwd",
+		"..\\irc.toml",
+		"..%5circ.toml",
+		"style.css%00.png",
+		"%ff%fe",
+		"..",
+		"%2e%2e",
+	];
+	
+	#[test]
+	fn relative () {
+		assert_eq! (relative_path ("css/style.css"), Ok (PathBuf::from ("css/style.css")));
+		assert_eq! (relative_path ("css//./style.css"), Ok (PathBuf::from ("css/style.css")));
+		assert_eq! (relative_path ("my%20game/game.html"), Ok (PathBuf::from ("my game/game.html")));
+		assert_eq! (relative_path (""), Ok (PathBuf::new ()));
+		
+		// Double-encoding only decodes once, so this is a harmless name
+		assert_eq! (relative_path ("%252e%252e/x"), Ok (PathBuf::from ("%2e%2e/x")));
+		
+		for tail in HOSTILE {
+			assert! (relative_path (tail).is_err (), "{}", tail);
+		}
+	}
+	
+	#[test]
+	fn segments () {
+		assert_eq! (url_segment ("pong.js"), "pong.js");
+		assert_eq! (url_segment ("a#b.js"), "a%23b.js");
+		assert_eq! (url_segment ("x?y"), "x%3Fy");
+		assert_eq! (url_segment ("my game"), "my%20game");
+		assert_eq! (url_segment ("100%"), "100%25");
+		
+		for name in &["a#b.js", "x?y", "my game", "%2e%2e", "café", "[1] {2}"] {
+			assert_eq! (relative_path (&url_segment (name)), Ok (PathBuf::from (name)), "{}", name);
+		}
+	}
+	
+	/// Builds a fake `game/` dir with a secret next to `static/` and
+	/// `git/repo.git`, the roots that `/static/` and `/git/` serve from
+	fn fixture () -> tempfile::TempDir {
+		let dir = tempfile::tempdir ().unwrap ();
+		let game = dir.path ();
+		
+		std::fs::write (game.join ("irc.toml"), "password = \"hunter2\"").unwrap ();
+		std::fs::create_dir_all (game.join ("static/css")).unwrap ();
+		std::fs::write (game.join ("static/css/style.css"), "body {}").unwrap ();
+		std::fs::create_dir_all (game.join ("git/repo.git/objects")).unwrap ();
+		std::fs::write (game.join ("git/repo.git/HEAD"), "ref: refs/heads/main").unwrap ();
+		
+		dir
+	}
+	
+	#[tokio::test]
+	async fn hostile_uris () {
+		let dir = fixture ();
+		let static_root = dir.path ().join ("static");
+		let git_root = dir.path ().join ("git/repo.git");
+		
+		assert! (resolve (&static_root, "css/style.css").await.is_ok ());
+		assert! (resolve (&git_root, "HEAD").await.is_ok ());
+		assert_eq! (resolve (&static_root, "nope.css").await, Err (UnsafePath::NotFound));
+		
+		for root in &[&static_root, &git_root] {
+			for tail in HOSTILE {
+				assert! (resolve (root, tail).await.is_err (), "{}", tail);
+			}
+		}
+	}
+	
+	#[cfg (unix)]
+	#[tokio::test]
+	async fn symlinks () {
+		let dir = fixture ();
+		let static_root = dir.path ().join ("static");
+		
+		std::os::unix::fs::symlink (dir.path ().join ("irc.toml"), static_root.join ("secret.txt")).unwrap ();
+		std::os::unix::fs::symlink (dir.path (), static_root.join ("up")).unwrap ();
+		std::os::unix::fs::symlink ("css/style.css", static_root.join ("inside.css")).unwrap ();
+		
+		assert_eq! (resolve (&static_root, "secret.txt").await, Err (UnsafePath::EscapesRoot));
+		assert_eq! (resolve (&static_root, "up/irc.toml").await, Err (UnsafePath::EscapesRoot));
+		assert! (resolve (&static_root, "inside.css").await.is_ok ());
+	}
+}
@@ -0,0 +1,202 @@
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	time::{Duration, SystemTime},
+};
+
+use anyhow::{
+	Context,
+	anyhow,
+};
+use handlebars::{
+	Handlebars,
+	handlebars_helper,
+};
+use serde::Serialize;
+
+use crate::assets;
+
+/// Pages go in the top level of this dir, shared pieces like the layout
+/// and menu go in `partials/`. With the `embed-assets` feature, files here
+/// override the ones built into the binary.
+const TEMPLATE_DIR: &str = "handlebars";
+
+/// All templates, compiled once. In dev mode they're recompiled whenever
+/// something in `handlebars/` changes.
+pub struct Templates {
+	dir: PathBuf,
+	registry: RwLock <Handlebars <'static>>,
+}
+
+impl Templates {
+	pub fn load () -> anyhow::Result <Self> {
+		Self::load_from (Path::new (TEMPLATE_DIR))
+	}
+	
+	pub fn load_from (dir: &Path) -> anyhow::Result <Self> {
+		Ok (Self {
+			dir: dir.to_path_buf (),
+			registry: RwLock::new (compile (dir)?),
+		})
+	}
+	
+	pub fn render <T: Serialize> (&self, name: &str, data: &T) -> anyhow::Result <String> {
+		let registry = self.registry.read ().map_err (|_| anyhow! ("Template lock is poisoned"))?;
+		
+		registry.render (name, data)
+		.with_context (|| format! ("Failed to render Handlebars template `{}`", name))
+	}
+	
+	/// Polls the template dir and recompiles when any file changes. If the
+	/// new templates don't compile, the old ones stay in use.
+	pub fn watch (self: Arc <Self>) {
+		tokio::spawn (async move {
+			let mut last_seen = fingerprint (&self.dir);
+			
+			loop {
+				tokio::time::sleep (Duration::from_secs (1)).await;
+				
+				let current = fingerprint (&self.dir);
+				if current == last_seen {
+					continue;
+				}
+				last_seen = current;
+				
+				match compile (&self.dir) {
+					Ok (registry) => {
+						if let Ok (mut x) = self.registry.write () {
+							*x = registry;
+							tracing::info! ("Reloaded templates");
+						}
+					},
+					Err (e) => tracing::error! ("Keeping old templates: {:?}", e),
+				}
+			}
+		});
+	}
+}
+
+fn hbs_files (dir: &Path) -> anyhow::Result <Vec <(String, PathBuf)>> {
+	let mut files = vec! [];
+	
+	for entry in std::fs::read_dir (dir).with_context (|| format! ("Can't read template dir `{}`", dir.display ()))? {
+		let path = entry?.path ();
+		if path.extension ().and_then (|s| s.to_str ()) != Some ("hbs") {
+			continue;
+		}
+		
+		let name = path.file_stem ()
+		.and_then (|s| s.to_str ())
+		.ok_or_else (|| anyhow! ("Template name isn't UTF-8: {:?}", path))?
+		.to_string ();
+		
+		files.push ((name, path));
+	}
+	
+	files.sort ();
+	Ok (files)
+}
+
+/// Template sources by name, starting from the embedded ones (if any)
+/// and letting files in `dir` replace them
+fn sources (embedded: Vec <(String, String)>, dir: &Path) -> anyhow::Result <BTreeMap <String, (String, String)>> {
+	let mut sources: BTreeMap <_, _> = embedded.into_iter ()
+	.map (|(name, source)| {
+		let origin = format! ("embedded {}", name);
+		(name, (origin, source))
+	})
+	.collect ();
+	
+	match hbs_files (dir) {
+		Ok (files) => for (name, path) in files {
+			let source = std::fs::read_to_string (&path)?;
+			sources.insert (name, (path.display ().to_string (), source));
+		},
+		// No dir on disk is fine if the binary brought its own templates
+		Err (_) if ! sources.is_empty () => (),
+		Err (e) => return Err (e),
+	}
+	
+	Ok (sources)
+}
+
+handlebars_helper! (human_size: |bytes: u64| crate::human_size (bytes));
+
+fn compile (dir: &Path) -> anyhow::Result <Handlebars <'static>> {
+	let mut registry = Handlebars::new ();
+	registry.register_helper ("human_size", Box::new (human_size));
+	
+	for (name, (origin, source)) in sources (assets::templates ("partials/"), &dir.join ("partials"))? {
+		registry.register_partial (&name, source)
+		.with_context (|| format! ("Failed to compile partial `{}`", origin))?;
+	}
+	
+	for (name, (origin, source)) in sources (assets::templates (""), dir)? {
+		registry.register_template_string (&name, source)
+		.with_context (|| format! ("Failed to compile template `{}`", origin))?;
+	}
+	
+	Ok (registry)
+}
+
+/// Names and mtimes of everything in the template dirs, to notice edits,
+/// new files and deleted files
+fn fingerprint (dir: &Path) -> Vec <(PathBuf, Option <SystemTime>)> {
+	let mut files = vec! [];
+	
+	for dir in &[dir.to_path_buf (), dir.join ("partials")] {
+		for (_, path) in hbs_files (dir).unwrap_or_default () {
+			let modified = std::fs::metadata (&path).and_then (|m| m.modified ()).ok ();
+			files.push ((path, modified));
+		}
+	}
+	
+	files
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn shipped_templates () {
+		#[derive (Serialize)]
+		struct Page {
+			holding_username: Option <String>,
+		}
+		
+		let templates = Templates::load ().unwrap ();
+		let html = templates.render ("commit", &Page {
+			holding_username: None,
+		}).unwrap ();
+		
+		assert! (html.contains ("<a href=\"commit\" class=\"highlighted\">Commit</a>"));
+		assert! (html.contains ("<a href=\"home\">Home</a>"));
+		assert! (html.contains ("<title>Commit | Code pong</title>"));
+	}
+	
+	#[test]
+	fn broken_template () {
+		let dir = tempfile::tempdir ().unwrap ();
+		std::fs::create_dir (dir.path ().join ("partials")).unwrap ();
+		std::fs::write (dir.path ().join ("good.hbs"), "{{x}}").unwrap ();
+		std::fs::write (dir.path ().join ("bad.hbs"), "{{#if x}} never closed").unwrap ();
+		
+		assert! (Templates::load_from (dir.path ()).is_err ());
+	}
+	
+	#[cfg (feature = "embed-assets")]
+	#[test]
+	fn disk_overrides_embedded () {
+		let dir = tempfile::tempdir ().unwrap ();
+		std::fs::write (dir.path ().join ("index.hbs"), "Local index").unwrap ();
+		
+		let templates = Templates::load_from (dir.path ()).unwrap ();
+		assert_eq! (templates.render ("index", &()).unwrap (), "Local index");
+		
+		// Partials and other pages still come from the binary
+		let html = templates.render ("next", &()).unwrap ();
+		assert! (html.contains ("<title>Next | Code pong</title>"));
+	}
+}
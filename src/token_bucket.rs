@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Allows bursts of up to `capacity`, then `refill_per_second` on average
+pub struct TokenBucket {
+	capacity: f64,
+	refill_per_second: f64,
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	pub fn new (capacity: f64, refill_per_second: f64, now: Instant) -> Self {
+		Self {
+			capacity,
+			refill_per_second,
+			tokens: capacity,
+			last_refill: now,
+		}
+	}
+	
+	fn refill (&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since (self.last_refill).as_secs_f64 ();
+		self.tokens = (self.tokens + elapsed * self.refill_per_second).min (self.capacity);
+		self.last_refill = now;
+	}
+	
+	pub fn try_take (&mut self, now: Instant) -> bool {
+		self.refill (now);
+		
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		}
+		else {
+			false
+		}
+	}
+	
+	/// A full bucket behaves like a brand new one, so it can be dropped
+	pub fn is_full (&mut self, now: Instant) -> bool {
+		self.refill (now);
+		self.tokens >= self.capacity
+	}
+	
+	pub fn time_until_token (&mut self, now: Instant) -> Duration {
+		self.refill (now);
+		
+		if self.tokens >= 1.0 {
+			Duration::from_secs (0)
+		}
+		else {
+			Duration::from_secs_f64 ((1.0 - self.tokens) / self.refill_per_second)
+		}
+	}
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn token_bucket () {
+		let start = Instant::now ();
+		let mut bucket = TokenBucket::new (2.0, 0.5, start);
+		
+		assert! (bucket.try_take (start));
+		assert! (bucket.try_take (start));
+		assert! (! bucket.try_take (start));
+		assert_eq! (bucket.time_until_token (start), Duration::from_secs (2));
+		
+		let later = start + Duration::from_secs (2);
+		assert! (bucket.try_take (later));
+		assert! (! bucket.try_take (later));
+		
+		let much_later = later + Duration::from_secs (60);
+		assert! (bucket.try_take (much_later));
+		assert! (bucket.try_take (much_later));
+		assert! (! bucket.try_take (much_later));
+	}
+}
@@ -0,0 +1,216 @@
+use std::path::Path;
+
+use git2::{
+	Oid,
+	Repository,
+};
+use serde::Serialize;
+
+use crate::{
+	error::Error,
+	history::{self, CommitData},
+};
+
+/// How many commits back to look for each entry's last change. Walking
+/// a long history for every listing is slow, and entries older than this
+/// just don't show one.
+const LAST_CHANGE_LIMIT: usize = 1_000;
+
+#[derive (Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde (rename_all = "lowercase")]
+pub enum Kind {
+	Dir,
+	File,
+	Symlink,
+	Submodule,
+}
+
+/// READMEs that are shown under a listing, best first
+const READMES: &[&str] = &["readme.md", "readme.markdown", "readme"];
+
+/// One entry of a directory listing, for `tree.hbs` and `/api/tree/`
+#[derive (Serialize)]
+pub struct Entry {
+	pub name: String,
+	pub kind: Kind,
+	
+	/// Git's file mode in octal, like `100644`
+	pub mode: String,
+	pub executable: bool,
+	
+	/// In bytes, for files only
+	pub size: Option <u64>,
+	pub symlink_target: Option <String>,
+	pub last_commit: Option <CommitData>,
+}
+
+/// Lists the directory `dir` in `commit`, directories first and then by
+/// name
+pub fn list (repo: &Repository, commit: Oid, dir: &Path) -> Result <Vec <Entry>, Error> {
+	let tree = repo.find_commit (commit)?.tree ()?;
+	let tree = match dir.as_os_str ().is_empty () {
+		true => tree,
+		false => tree.get_path (dir)
+		.map_err (|_| Error::not_found (format! ("No file or directory at `{}` in commit {}", dir.display (), commit)))?
+		.to_object (repo)?
+		.into_tree ()
+		.map_err (|_| Error::bad_request (format! ("`{}` isn't a directory", dir.display ())))?,
+	};
+	
+	let odb = repo.odb ()?;
+	let mut entries = vec! [];
+	for entry in tree.iter () {
+		// Non-UTF-8 names can't be linked to anyway
+		let name = match entry.name () {
+			Some (x) => x.to_string (),
+			None => continue,
+		};
+		
+		let mode = entry.filemode ();
+		let kind = match mode & 0o170000 {
+			0o040000 => Kind::Dir,
+			0o120000 => Kind::Symlink,
+			0o160000 => Kind::Submodule,
+			_ => Kind::File,
+		};
+		
+		// Only the header is read for a file's size, so big assets don't
+		// get inflated. A symlink's blob is its target.
+		let (size, symlink_target) = match kind {
+			Kind::File => (Some (odb.read_header (entry.id ())?.0 as u64), None),
+			Kind::Symlink => (None, Some (String::from_utf8_lossy (repo.find_blob (entry.id ())?.content ()).to_string ())),
+			_ => (None, None),
+		};
+		
+		entries.push (Entry {
+			name,
+			kind,
+			mode: format! ("{:06o}", mode),
+			executable: kind == Kind::File && mode & 0o111 != 0,
+			size,
+			symlink_target,
+			last_commit: None,
+		});
+	}
+	
+	entries.sort_by (|a, b| (a.kind != Kind::Dir, &a.name).cmp (&(b.kind != Kind::Dir, &b.name)));
+	
+	let names: Vec <_> = entries.iter ().map (|e| e.name.clone ()).collect ();
+	let last_changes = history::last_changes (repo, commit, dir, &names, LAST_CHANGE_LIMIT)?;
+	for (entry, last_commit) in entries.iter_mut ().zip (last_changes) {
+		entry.last_commit = last_commit;
+	}
+	
+	Ok (entries)
+}
+
+/// The README to show under a listing, if there is one
+pub fn readme (entries: &[Entry]) -> Option <&Entry> {
+	READMES.iter ()
+	.find_map (|readme| entries.iter ().find (|e| e.kind == Kind::File && e.name.eq_ignore_ascii_case (readme)))
+}
+
+#[cfg (test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn listing () {
+		let dir = tempfile::tempdir ().unwrap ();
+		let repo = Repository::init (dir.path ()).unwrap ();
+		
+		let mut time = 1_600_000_000;
+		let mut commit = |author: &str, pong: &[u8], parents: &[Oid]| {
+			time += 60;
+			let sig = git2::Signature::new (author, "player@example.com", &git2::Time::new (time, 0)).unwrap ();
+			
+			let mut js = repo.treebuilder (None).unwrap ();
+			js.insert ("pong.js", repo.blob (pong).unwrap (), 0o100644).unwrap ();
+			let js = js.write ().unwrap ();
+			
+			let mut tree = repo.treebuilder (None).unwrap ();
+			tree.insert ("js", js, 0o040000).unwrap ();
+			tree.insert ("build.sh", repo.blob (b"#!/bin/sh\n").unwrap (), 0o100755).unwrap ();
+			tree.insert ("game.html", repo.blob (b"<p>Pong</p>").unwrap (), 0o100644).unwrap ();
+			tree.insert ("index.html", repo.blob (b"game.html").unwrap (), 0o120000).unwrap ();
+			tree.insert ("engine", Oid::from_str ("0123456789012345678901234567890123456789").unwrap (), 0o160000).unwrap ();
+			let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+			
+			let parents: Vec <_> = parents.iter ().map (|p| repo.find_commit (*p).unwrap ()).collect ();
+			let parents: Vec <_> = parents.iter ().collect ();
+			repo.commit (None, &sig, &sig, author, &tree, &parents).unwrap ()
+		};
+		
+		let start = commit ("alice", b"pong ()", &[]);
+		let faster = commit ("bob", b"pong (2)", &[start]);
+		
+		let entries = list (&repo, faster, Path::new ("")).unwrap ();
+		let names: Vec <_> = entries.iter ().map (|e| (e.name.as_str (), e.kind)).collect ();
+		assert_eq! (names, vec! [
+			("js", Kind::Dir),
+			("build.sh", Kind::File),
+			("engine", Kind::Submodule),
+			("game.html", Kind::File),
+			("index.html", Kind::Symlink),
+		]);
+		
+		assert! (entries [1].executable);
+		assert_eq! (entries [1].mode, "100755");
+		assert! (! entries [3].executable);
+		assert_eq! (entries [3].size, Some (11));
+		assert_eq! (entries [4].size, None);
+		assert_eq! (entries [4].symlink_target.as_deref (), Some ("game.html"));
+		
+		let authors: Vec <_> = entries.iter ()
+		.map (|e| e.last_commit.as_ref ().and_then (|c| c.author.as_deref ()))
+		.collect ();
+		assert_eq! (authors, vec! [Some ("bob"), Some ("alice"), Some ("alice"), Some ("alice"), Some ("alice")]);
+		
+		let js = list (&repo, faster, Path::new ("js")).unwrap ();
+		assert_eq! (js [0].name, "pong.js");
+		assert_eq! (js [0].last_commit.as_ref ().unwrap ().id, faster.to_string ());
+		assert_eq! (list (&repo, start, Path::new ("js")).unwrap () [0].last_commit.as_ref ().unwrap ().id, start.to_string ());
+		
+		assert! (matches! (list (&repo, faster, Path::new ("nope")), Err (Error::NotFound (_))));
+		assert! (matches! (list (&repo, faster, Path::new ("game.html")), Err (Error::BadRequest (_))));
+		
+		assert! (readme (&entries).is_none ());
+		
+		let js_tree = repo.find_commit (faster).unwrap ().tree ().unwrap ().get_name ("js").unwrap ().id ();
+		let readme_in = |names: &[(&str, i32)]| {
+			let sig = git2::Signature::now ("carol", "player@example.com").unwrap ();
+			let mut tree = repo.treebuilder (None).unwrap ();
+			for (name, mode) in names {
+				let oid = match *mode {
+					0o040000 => js_tree,
+					_ => repo.blob (name.as_bytes ()).unwrap (),
+				};
+				tree.insert (name, oid, *mode).unwrap ();
+			}
+			let tree = repo.find_tree (tree.write ().unwrap ()).unwrap ();
+			let oid = repo.commit (None, &sig, &sig, "README", &tree, &[]).unwrap ();
+			
+			let entries = list (&repo, oid, Path::new ("")).unwrap ();
+			readme (&entries).map (|e| e.name.clone ())
+		};
+		
+		assert_eq! (readme_in (&[
+			("README", 0o100644),
+			("readme.markdown", 0o100644),
+			("Readme.MD", 0o100644),
+		]).as_deref (), Some ("Readme.MD"));
+		assert_eq! (readme_in (&[
+			("README", 0o100644),
+			("README.markdown", 0o100644),
+			("README.md", 0o040000),
+		]).as_deref (), Some ("README.markdown"));
+		assert_eq! (readme_in (&[
+			("readme", 0o100644),
+			("readme.txt", 0o100644),
+		]).as_deref (), Some ("readme"));
+		assert_eq! (readme_in (&[
+			("README.md", 0o120000),
+			("readme.txt", 0o100644),
+		]), None);
+	}
+}